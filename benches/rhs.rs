@@ -0,0 +1,65 @@
+//! Compares the right-hand side with the name-keyed map lookups the old code made on
+//! every call against the compiled struct, plus a full `evaluate`.
+//!
+//! Run with `cargo bench --bench rhs`.
+
+use cardiovascular_model_fitting::model::parameters::{CompiledParams, HemodynamicParams};
+use cardiovascular_model_fitting::model::simulation::{self, Objective, SimulationSettings, TargetMetric};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Parameters the old right-hand side looked up in the map on every call, in order
+/// and with repeats.
+const RHS_LOOKUPS: [&str; 65] = [
+    "LV_Ees", "LV_V0", "LV_alpha", "LV_beta", "LV_Tmax", "LV_tau", "LV_AV_delay", "HR", "LA_Ees", "LA_V0",
+    "LA_alpha", "LA_beta", "LA_Tmax", "LA_tau", "LA_AV_delay", "HR", "RV_Ees", "RV_V0", "RV_alpha", "RV_beta",
+    "RV_Tmax", "RV_tau", "RV_AV_delay", "HR", "RA_Ees", "RA_V0", "RA_alpha", "RA_beta", "RA_Tmax", "RA_tau",
+    "RA_AV_delay", "HR", "Cas_prox", "Cda", "Rda", "Cda", "Cas", "Ras", "Cas", "Cvs", "Rcs", "Cvs", "Rvs", "Cvp",
+    "Rvp", "Cap", "Cvp", "Rap", "Cap_prox", "Cap", "Rcp", "Rtv", "Rtvs", "Rtvr", "Rmv", "Rmvs", "Rmvr",
+    "Cas_prox", "Ras_prox", "Ravs", "Ravr", "Cap_prox", "Rap_prox", "Rpvs", "Rpvr",
+];
+
+fn time<F: FnMut()>(label: &str, iterations: u32, mut f: F) -> Duration {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iter = start.elapsed() / iterations;
+    println!("{:<40} {:>12?} / iter", label, per_iter);
+    per_iter
+}
+
+fn main() {
+    let params = HemodynamicParams::new();
+    let state = simulation::initial_state(&params).expect("default parameters are valid");
+    let compiled = CompiledParams::try_from(&params).expect("default parameters are valid");
+    let iterations = 200_000;
+
+    // The same arithmetic plus the old per-call lookups, which is what the map-based
+    // right-hand side cost.
+    let lookup = time("rhs, 65 map lookups per call", iterations, || {
+        let map = &black_box(&params).parameters;
+        let mut values = [0.0; RHS_LOOKUPS.len()];
+        for (value, name) in values.iter_mut().zip(RHS_LOOKUPS) {
+            *value = map[name].value;
+        }
+        black_box(values);
+        black_box(simulation::pv_func(black_box(100.0), black_box(&state), black_box(&compiled)));
+    });
+    let flat = time("rhs, compiled once", iterations, || {
+        black_box(simulation::pv_func(black_box(100.0), black_box(&state), black_box(&compiled)));
+    });
+    println!("rhs speedup: {:.1}x", lookup.as_secs_f64() / flat.as_secs_f64());
+
+    let settings = SimulationSettings::default();
+    let objective = Objective::new(
+        vec![TargetMetric { metric: "stroke_volume".to_string(), target: 60.0, weight: 1.0, tolerance: 0.0 }],
+        Vec::new(),
+    );
+    time("evaluate (steady state + metrics)", 20, || {
+        black_box(simulation::evaluate(black_box(&params), &objective, &settings).unwrap());
+    });
+}
@@ -0,0 +1,556 @@
+//! Batch fitting of patient cohorts. A batch is one job: it takes one worker slot
+//! and runs its patients side by side on that slot's threads.
+
+use crate::api::jobs::{JobStatus, SharedJobStore};
+use crate::api::routes::{self, ConditionParams, OptimizationParams, OptimizationResult};
+use crate::api::validation::{self, ValidationErrors};
+use crate::error::ModelError;
+use crate::model::parameters::{LegacyParamUpdate, ParamUpdate};
+use crate::model::simulation::{RunControl, StopReason, TargetMetric};
+use crate::model::waveform::WaveformTarget;
+
+use axum::{
+    extract::{rejection::JsonRejection, Json, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+
+/// Largest cohort accepted in one batch.
+const MAX_PATIENTS: usize = 10_000;
+
+/// Body of a batch request: settings shared by every patient, written like an
+/// optimization request, plus the patient records.
+#[derive(Deserialize)]
+pub struct BatchParams<T = TargetMetric, U = ParamUpdate> {
+    pub patients: Vec<PatientRecord<T, U>>,
+    #[serde(flatten)]
+    pub shared: OptimizationParams<T, U>,
+}
+
+/// One patient's measurements. Targets given here replace the shared ones;
+/// `param_updates` are laid field by field over the shared updates.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatientRecord<T = TargetMetric, U = ParamUpdate> {
+    pub id: String,
+    #[serde(default = "Vec::new")]
+    pub target_metrics: Vec<T>,
+    #[serde(default)]
+    pub waveform_targets: Vec<WaveformTarget>,
+    #[serde(default = "Vec::new")]
+    pub conditions: Vec<ConditionParams<T>>,
+    pub param_updates: Option<FxHashMap<String, U>>,
+}
+
+pub type BatchParamsV1 = BatchParams<(f64, String, f64), LegacyParamUpdate>;
+
+impl<T: Into<TargetMetric>, U: Into<ParamUpdate>> BatchParams<T, U> {
+    /// The full optimization request of every patient, in input order.
+    pub fn into_requests(self) -> Vec<(String, OptimizationParams)> {
+        let shared = self.shared.upgrade();
+        self.patients
+            .into_iter()
+            .map(|patient| {
+                let mut params = shared.clone();
+                if !patient.target_metrics.is_empty() {
+                    params.target_metrics = patient.target_metrics.into_iter().map(Into::into).collect();
+                }
+                if !patient.waveform_targets.is_empty() {
+                    params.waveform_targets = patient.waveform_targets;
+                }
+                if !patient.conditions.is_empty() {
+                    params.conditions = patient.conditions.into_iter().map(ConditionParams::upgrade).collect();
+                }
+                if let Some(updates) = routes::upgrade_updates(patient.param_updates) {
+                    let merged = params.param_updates.get_or_insert_with(FxHashMap::default);
+                    for (name, update) in updates {
+                        let shared = merged.get(&name).copied().unwrap_or_default();
+                        merged.insert(name, update.over(shared));
+                    }
+                }
+                (patient.id, params)
+            })
+            .collect()
+    }
+}
+
+/// Builds and validates every patient's request.
+pub fn parse_batch<T, U>(params: BatchParams<T, U>) -> Result<Vec<(String, OptimizationParams)>, ValidationErrors>
+where
+    T: Into<TargetMetric>,
+    U: Into<ParamUpdate>,
+{
+    let requests = params.into_requests();
+    validate(&requests)?;
+    Ok(requests)
+}
+
+/// Checks every patient's request. Errors in what a patient can set are reported
+/// under `patients[i].`; errors in the shared settings are reported once.
+fn validate(requests: &[(String, OptimizationParams)]) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    errors.check(
+        (1..=MAX_PATIENTS).contains(&requests.len()),
+        "patients",
+        format!("must contain between 1 and {} patients", MAX_PATIENTS),
+    );
+    let patient_fields = ["target_metrics", "waveform_targets", "conditions", "param_updates"];
+    for (i, (id, params)) in requests.iter().enumerate() {
+        let field = format!("patients[{}].id", i);
+        errors.check(!id.is_empty(), &field, "must not be empty");
+        errors.check(
+            id.is_empty() || requests[..i].iter().all(|(other, _)| other != id),
+            &field,
+            format!("duplicate patient id '{}'", id),
+        );
+        if let Err(invalid) = params.validate() {
+            for error in invalid.errors {
+                let field = if patient_fields.iter().any(|name| error.field.starts_with(name)) {
+                    format!("patients[{}].{}", i, error.field)
+                } else {
+                    error.field
+                };
+                if !errors.errors.iter().any(|seen| seen.field == field && seen.message == error.message) {
+                    errors.add(field, error.message);
+                }
+            }
+        }
+    }
+    errors.into_result()
+}
+
+#[derive(Clone, Serialize)]
+pub struct PatientResult {
+    pub id: String,
+    pub status: JobStatus,
+    pub result: Option<OptimizationResult>,
+    /// Fitted values of the patient's targeted metrics.
+    pub metrics: BTreeMap<String, f64>,
+    pub error: Option<String>,
+}
+
+impl PatientResult {
+    fn queued(id: String) -> Self {
+        PatientResult {
+            id,
+            status: JobStatus::Queued,
+            result: None,
+            metrics: BTreeMap::new(),
+            error: None,
+        }
+    }
+}
+
+pub struct Batch {
+    status: JobStatus,
+    control: RunControl,
+    patients: Vec<PatientResult>,
+    /// When the batch completed, failed or was cancelled; it is evicted a TTL later.
+    pub(super) finished_at: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub struct BatchView {
+    pub id: String,
+    pub status: JobStatus,
+    pub total: usize,
+    /// Patients that have finished, successfully or not.
+    pub finished: usize,
+    pub patients: Vec<PatientResult>,
+}
+
+fn view(store: &SharedJobStore, id: &str) -> Option<BatchView> {
+    let batches = store.batches.lock().unwrap();
+    batches.get(id).map(|batch| BatchView {
+        id: id.to_string(),
+        status: batch.status,
+        total: batch.patients.len(),
+        finished: batch
+            .patients
+            .iter()
+            .filter(|patient| !matches!(patient.status, JobStatus::Queued | JobStatus::Running))
+            .count(),
+        patients: batch.patients.clone(),
+    })
+}
+
+/// Values of `names` among a fit's metrics, for the cohort summary.
+fn targeted_metrics(result: &OptimizationResult, names: &[String]) -> BTreeMap<String, f64> {
+    names.iter().filter_map(|name| result.metrics.get(name).map(|&value| (name.clone(), value))).collect()
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "patient fit panicked".to_string())
+}
+
+/// Runs all patients on `threads` threads. Patients run side by side with a few
+/// threads each, which keeps every core busy with far less coordination than
+/// spreading each fit across the whole machine. `report` sees each patient when it
+/// starts and when it finishes; patients left unstarted by cancellation come back
+/// cancelled, and every patient fails if the thread pool cannot be built.
+pub fn run_patients(
+    requests: Vec<(String, OptimizationParams)>,
+    threads: usize,
+    control: &RunControl,
+    report: impl Fn(usize, &PatientResult) + Sync,
+) -> Vec<PatientResult> {
+    let threads_per_patient = (threads / requests.len().max(1)).max(1);
+    let pool = match ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool,
+        Err(err) => {
+            let message = ModelError::from(err).to_string();
+            return requests
+                .into_iter()
+                .map(|(id, _)| PatientResult {
+                    status: JobStatus::Failed,
+                    error: Some(message.clone()),
+                    ..PatientResult::queued(id)
+                })
+                .collect();
+        }
+    };
+    let mut patients: Vec<PatientResult> = pool.install(|| {
+        requests
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, (id, params))| {
+                let mut patient = PatientResult::queued(id);
+                if control.is_cancelled() {
+                    return patient;
+                }
+                patient.status = JobStatus::Running;
+                report(index, &patient);
+
+                let targeted: Vec<String> = params.target_metrics.iter().map(|target| target.metric.clone()).collect();
+                let outcome =
+                    panic::catch_unwind(AssertUnwindSafe(|| routes::run_request(params, threads_per_patient, control)));
+                match outcome {
+                    Ok(Ok(result)) => {
+                        patient.status = if control.is_cancelled() { JobStatus::Cancelled } else { JobStatus::Completed };
+                        patient.metrics = targeted_metrics(&result, &targeted);
+                        patient.result = Some(result);
+                    }
+                    Ok(Err(err)) => {
+                        patient.status = JobStatus::Failed;
+                        patient.error = Some(err.to_string());
+                    }
+                    Err(payload) => {
+                        patient.status = JobStatus::Failed;
+                        patient.error = Some(panic_message(payload));
+                    }
+                }
+                report(index, &patient);
+                patient
+            })
+            .collect()
+    });
+    finish_unstarted(&mut patients);
+    patients
+}
+
+async fn run_batch(store: SharedJobStore, id: String, requests: Vec<(String, OptimizationParams)>, control: RunControl) {
+    let _permit = store.workers.acquire().await.unwrap();
+    if control.is_cancelled() {
+        return;
+    }
+    if let Some(batch) = store.batches.lock().unwrap().get_mut(&id) {
+        batch.status = JobStatus::Running;
+    }
+
+    let threads = store.threads_per_job;
+    let (worker_store, worker_id, worker_control) = (store.clone(), id.clone(), control.clone());
+    let outcome = tokio::task::spawn_blocking(move || {
+        run_patients(requests, threads, &worker_control, |index, patient| {
+            let mut batches = worker_store.batches.lock().unwrap();
+            if let Some(slot) = batches.get_mut(&worker_id).and_then(|batch| batch.patients.get_mut(index)) {
+                *slot = patient.clone();
+            }
+        })
+    })
+    .await;
+
+    let mut batches = store.batches.lock().unwrap();
+    if let Some(batch) = batches.get_mut(&id) {
+        batch.status = match outcome {
+            Err(_) => JobStatus::Failed,
+            Ok(_) if control.is_cancelled() => JobStatus::Cancelled,
+            Ok(_) => JobStatus::Completed,
+        };
+        if let Ok(patients) = outcome {
+            batch.patients = patients;
+        }
+        finish_unstarted(&mut batch.patients);
+        batch.finished_at = Some(Instant::now());
+    }
+}
+
+/// Marks patients that never ran once the batch is over.
+fn finish_unstarted(patients: &mut [PatientResult]) {
+    for patient in patients {
+        if matches!(patient.status, JobStatus::Queued | JobStatus::Running) {
+            patient.status = JobStatus::Cancelled;
+        }
+    }
+}
+
+fn submit_batch(store: SharedJobStore, requests: Vec<(String, OptimizationParams)>) -> Response {
+    store.prune();
+    let id = format!("{:016x}", rand::random::<u64>());
+    let control = RunControl::new();
+    let patients = requests.iter().map(|(patient_id, _)| PatientResult::queued(patient_id.clone())).collect();
+    store.batches.lock().unwrap().insert(
+        id.clone(),
+        Batch {
+            status: JobStatus::Queued,
+            control: control.clone(),
+            patients,
+            finished_at: None,
+        },
+    );
+
+    tokio::spawn(run_batch(store.clone(), id.clone(), requests, control));
+
+    (StatusCode::ACCEPTED, Json(view(&store, &id))).into_response()
+}
+
+pub async fn create_batch(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<BatchParamsV1>, JsonRejection>,
+) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => match parse_batch(params) {
+            Ok(requests) => submit_batch(store, requests),
+            Err(errors) => errors.into_response(),
+        },
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn create_batch_v2(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<BatchParams>, JsonRejection>,
+) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => match parse_batch(params) {
+            Ok(requests) => submit_batch(store, requests),
+            Err(errors) => errors.into_response(),
+        },
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn get_batch(State(store): State<SharedJobStore>, Path(id): Path<String>) -> impl IntoResponse {
+    store.prune();
+    match view(&store, &id) {
+        Some(view) => Ok(Json(view)),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Cancels a batch. Patients that have not started are skipped; running fits stop
+/// after their current generation and keep their best result so far.
+pub async fn cancel_batch(State(store): State<SharedJobStore>, Path(id): Path<String>) -> impl IntoResponse {
+    {
+        let mut batches = store.batches.lock().unwrap();
+        let Some(batch) = batches.get_mut(&id) else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        batch.control.cancel();
+        if batch.status == JobStatus::Queued {
+            batch.status = JobStatus::Cancelled;
+            finish_unstarted(&mut batch.patients);
+            batch.finished_at = Some(Instant::now());
+        }
+    }
+    Ok(Json(view(&store, &id)))
+}
+
+/// One row of the cohort summary table.
+#[derive(Serialize)]
+pub struct SummaryRow {
+    pub id: String,
+    pub status: JobStatus,
+    pub best_fitness: Option<f64>,
+    pub converged: Option<bool>,
+    pub stop_reason: Option<StopReason>,
+    /// Values of the fitted parameters.
+    pub parameters: BTreeMap<String, f64>,
+    /// Fitted values of the targeted metrics.
+    pub metrics: BTreeMap<String, f64>,
+}
+
+impl From<&PatientResult> for SummaryRow {
+    fn from(patient: &PatientResult) -> Self {
+        let result = patient.result.as_ref();
+        SummaryRow {
+            id: patient.id.clone(),
+            status: patient.status,
+            best_fitness: result.map(|result| result.best_fitness),
+            converged: result.map(|result| result.steady_state.converged),
+            stop_reason: result.map(|result| result.stop_reason),
+            parameters: result
+                .map(|result| {
+                    result
+                        .best_parameters
+                        .parameters
+                        .iter()
+                        .filter(|(_, param)| param.fitting)
+                        .map(|(name, param)| (name.clone(), param.value))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            metrics: patient.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    #[serde(default)]
+    pub format: SummaryFormat,
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Renders the summary with one column per fitted parameter and targeted metric
+/// seen anywhere in the cohort; cells a patient has no value for stay empty.
+pub fn summary_csv(rows: &[SummaryRow]) -> String {
+    let mut parameters: Vec<&String> = rows.iter().flat_map(|row| row.parameters.keys()).collect();
+    parameters.sort();
+    parameters.dedup();
+    let mut metrics: Vec<&String> = rows.iter().flat_map(|row| row.metrics.keys()).collect();
+    metrics.sort();
+    metrics.dedup();
+
+    let json_text = |value: serde_json::Value| value.as_str().map(str::to_string).unwrap_or_default();
+    let number = |value: Option<&f64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    let mut header = vec!["id", "status", "best_fitness", "converged", "stop_reason"]
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    header.extend(parameters.iter().map(|name| name.to_string()));
+    header.extend(metrics.iter().map(|name| name.to_string()));
+    let mut csv = header.iter().map(|name| csv_field(name)).collect::<Vec<_>>().join(",") + "\n";
+
+    for row in rows {
+        let mut cells = vec![
+            row.id.clone(),
+            json_text(serde_json::json!(row.status)),
+            number(row.best_fitness.as_ref()),
+            row.converged.map(|converged| converged.to_string()).unwrap_or_default(),
+            row.stop_reason.map(|reason| json_text(serde_json::json!(reason))).unwrap_or_default(),
+        ];
+        cells.extend(parameters.iter().map(|name| number(row.parameters.get(*name))));
+        cells.extend(metrics.iter().map(|name| number(row.metrics.get(*name))));
+        csv += &(cells.iter().map(|cell| csv_field(cell)).collect::<Vec<_>>().join(",") + "\n");
+    }
+    csv
+}
+
+/// Cohort summary table of a batch, one row per patient, as JSON or CSV.
+pub async fn batch_summary(
+    State(store): State<SharedJobStore>,
+    Path(id): Path<String>,
+    Query(query): Query<SummaryQuery>,
+) -> Response {
+    store.prune();
+    let Some(view) = view(&store, &id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let rows: Vec<SummaryRow> = view.patients.iter().map(SummaryRow::from).collect();
+    match query.format {
+        SummaryFormat::Json => Json(rows).into_response(),
+        SummaryFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"batch-{}.csv\"", id)),
+            ],
+            summary_csv(&rows),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: &str, parameters: &[(&str, f64)], metrics: &[(&str, f64)]) -> SummaryRow {
+        SummaryRow {
+            id: id.to_string(),
+            status: JobStatus::Completed,
+            best_fitness: Some(0.5),
+            converged: Some(true),
+            stop_reason: Some(StopReason::MaxGenerations),
+            parameters: parameters.iter().map(|&(name, value)| (name.to_string(), value)).collect(),
+            metrics: metrics.iter().map(|&(name, value)| (name.to_string(), value)).collect(),
+        }
+    }
+
+    #[test]
+    fn summary_csv_quotes_fields_and_unions_columns() {
+        let rows = [
+            row("plain", &[("Rcs", 1.5)], &[("stroke_volume", 60.0)]),
+            row("has,comma \"and\" quotes", &[("Ees", 2.0)], &[]),
+            row("line\nbreak", &[], &[]),
+        ];
+        assert_eq!(
+            summary_csv(&rows),
+            "id,status,best_fitness,converged,stop_reason,Ees,Rcs,stroke_volume\n\
+             plain,completed,0.5,true,max_generations,,1.5,60\n\
+             \"has,comma \"\"and\"\" quotes\",completed,0.5,true,max_generations,2,,\n\
+             \"line\nbreak\",completed,0.5,true,max_generations,,,\n"
+        );
+    }
+
+    #[test]
+    fn patient_param_updates_merge_field_by_field() {
+        let body = serde_json::json!({
+            "num_repeats": 1,
+            "param_updates": {"LV_Ees": [2.0, [1.0, 3.0], true], "Rcs": [1.5, null, false]},
+            "patients": [
+                {"id": "P001", "param_updates": {"LV_Ees": [2.5, null, null], "HR": [72.0, null, false]}},
+                {"id": "P002"}
+            ]
+        });
+        let batch: BatchParamsV1 = serde_json::from_value(body).unwrap();
+        let requests = batch.into_requests();
+
+        let updates = requests[0].1.param_updates.as_ref().unwrap();
+        let ees = updates["LV_Ees"];
+        assert_eq!((ees.value, ees.min, ees.max, ees.fit), (Some(2.5), Some(1.0), Some(3.0), Some(true)));
+        assert_eq!(updates["HR"].value, Some(72.0));
+        assert_eq!(updates["Rcs"].value, Some(1.5));
+
+        let shared = requests[1].1.param_updates.as_ref().unwrap();
+        assert_eq!(shared["LV_Ees"].value, Some(2.0));
+        assert!(!shared.contains_key("HR"));
+    }
+}
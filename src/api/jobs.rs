@@ -0,0 +1,267 @@
+use crate::api::batch::Batch;
+use crate::api::routes::{self, OptimizationParams, OptimizationParamsV1, OptimizationResult};
+use crate::model::simulation::{Progress, RunControl};
+
+use axum::{
+    extract::{rejection::JsonRejection, Json, Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use fxhash::FxHashMap;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Semaphore};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// Message on a job's event stream.
+#[derive(Clone)]
+enum JobEvent {
+    Progress(Box<Progress>),
+    Finished(JobStatus),
+}
+
+impl JobEvent {
+    fn to_sse(&self) -> Event {
+        let event = match self {
+            JobEvent::Progress(progress) => Event::default().event("progress").json_data(progress),
+            JobEvent::Finished(status) => Event::default().event("finished").json_data(status),
+        };
+        event.unwrap_or_default()
+    }
+}
+
+struct Job {
+    status: JobStatus,
+    control: RunControl,
+    result: Option<OptimizationResult>,
+    error: Option<String>,
+    /// Dropped once the job has finished, which ends every open event stream.
+    events: Option<broadcast::Sender<JobEvent>>,
+    finished_at: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub struct JobView {
+    pub id: String,
+    pub status: JobStatus,
+    /// Best result so far; also set for jobs cancelled mid-run.
+    pub result: Option<OptimizationResult>,
+    pub error: Option<String>,
+}
+
+/// In-memory registry of optimization jobs and batches, run by a bounded number of
+/// workers. Finished entries are dropped once they are older than the store's TTL.
+pub struct JobStore {
+    jobs: Mutex<FxHashMap<String, Job>>,
+    pub(super) batches: Mutex<FxHashMap<String, Batch>>,
+    pub(super) workers: Semaphore,
+    pub(super) threads_per_job: usize,
+    ttl: Duration,
+}
+
+pub type SharedJobStore = Arc<JobStore>;
+
+impl JobStore {
+    /// `max_concurrent` fits run at once; the machine's threads are split between them.
+    pub fn new(max_concurrent: usize) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            jobs: Mutex::new(FxHashMap::default()),
+            batches: Mutex::new(FxHashMap::default()),
+            workers: Semaphore::new(max_concurrent),
+            threads_per_job: (routes::available_threads() / max_concurrent).max(1),
+            ttl: Duration::from_secs(3600),
+        }
+    }
+
+    /// How long finished jobs and batches stay available; one hour by default.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Whether an entry that finished at `finished_at` is still kept.
+    pub(super) fn is_live(&self, finished_at: Option<Instant>) -> bool {
+        finished_at.is_none_or(|at| at.elapsed() < self.ttl)
+    }
+
+    /// Drops finished entries older than the TTL.
+    pub(super) fn prune(&self) {
+        self.jobs.lock().unwrap().retain(|_, job| self.is_live(job.finished_at));
+        self.batches.lock().unwrap().retain(|_, batch| self.is_live(batch.finished_at));
+    }
+
+    fn view(&self, id: &str) -> Option<JobView> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id).map(|job| JobView {
+            id: id.to_string(),
+            status: job.status,
+            result: job.result.clone(),
+            error: job.error.clone(),
+        })
+    }
+
+    fn publish(&self, id: &str, event: JobEvent) {
+        if let Some(events) = self.jobs.lock().unwrap().get(id).and_then(|job| job.events.as_ref()) {
+            let _ = events.send(event);
+        }
+    }
+
+    fn set_status(&self, id: &str, status: JobStatus) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.status = status;
+        }
+    }
+}
+
+async fn run_job(store: SharedJobStore, id: String, params: OptimizationParams, control: RunControl) {
+    let _permit = store.workers.acquire().await.unwrap();
+    if control.is_cancelled() {
+        return;
+    }
+    store.set_status(&id, JobStatus::Running);
+
+    let threads = store.threads_per_job;
+    let worker_control = control.clone();
+    let outcome = tokio::task::spawn_blocking(move || routes::run_request(params, threads, &worker_control)).await;
+
+    let mut jobs = store.jobs.lock().unwrap();
+    if let Some(job) = jobs.get_mut(&id) {
+        match outcome {
+            Ok(Ok(result)) => {
+                job.status = if control.is_cancelled() { JobStatus::Cancelled } else { JobStatus::Completed };
+                job.result = Some(result);
+            }
+            Ok(Err(err)) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(err.to_string());
+            }
+            Err(err) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(err.to_string());
+            }
+        }
+        finish_events(job);
+    }
+}
+
+fn finish_events(job: &mut Job) {
+    job.finished_at = Some(Instant::now());
+    if let Some(events) = job.events.take() {
+        let _ = events.send(JobEvent::Finished(job.status));
+    }
+}
+
+pub async fn create_job(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<OptimizationParamsV1>, JsonRejection>,
+) -> Response {
+    match routes::parse_optimization(payload) {
+        Ok(params) => submit_job(store, params),
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn create_job_v2(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<OptimizationParams>, JsonRejection>,
+) -> Response {
+    match routes::parse_optimization(payload) {
+        Ok(params) => submit_job(store, params),
+        Err(errors) => errors.into_response(),
+    }
+}
+
+fn submit_job(store: SharedJobStore, params: OptimizationParams) -> Response {
+    store.prune();
+    let id = format!("{:016x}", rand::random::<u64>());
+    let (events, _) = broadcast::channel(64);
+    let publisher = Arc::downgrade(&store);
+    let job_id = id.clone();
+    let control = RunControl::new().with_progress(move |progress| {
+        if let Some(store) = publisher.upgrade() {
+            store.publish(&job_id, JobEvent::Progress(Box::new(progress.clone())));
+        }
+    });
+    store.jobs.lock().unwrap().insert(
+        id.clone(),
+        Job {
+            status: JobStatus::Queued,
+            control: control.clone(),
+            result: None,
+            error: None,
+            events: Some(events),
+            finished_at: None,
+        },
+    );
+
+    tokio::spawn(run_job(store.clone(), id.clone(), params, control));
+
+    let view = store.view(&id);
+    (StatusCode::ACCEPTED, Json(view)).into_response()
+}
+
+pub async fn get_job(State(store): State<SharedJobStore>, Path(id): Path<String>) -> impl IntoResponse {
+    store.prune();
+    match store.view(&id) {
+        Some(view) => Ok(Json(view)),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Cancels a job. Queued jobs never start; running jobs stop after the current
+/// generation and keep their best result so far.
+pub async fn cancel_job(State(store): State<SharedJobStore>, Path(id): Path<String>) -> impl IntoResponse {
+    {
+        let mut jobs = store.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&id) else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        job.control.cancel();
+        if job.status == JobStatus::Queued {
+            job.status = JobStatus::Cancelled;
+            finish_events(job);
+        }
+    }
+    Ok(Json(store.view(&id)))
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>;
+
+/// Streams a job's per-generation progress as Server-Sent Events.
+///
+/// Emits `progress` events while the job runs and a final `finished` event with
+/// the job status, after which the stream closes.
+pub async fn job_events(
+    State(store): State<SharedJobStore>,
+    Path(id): Path<String>,
+) -> Result<Sse<EventStream>, StatusCode> {
+    let jobs = store.jobs.lock().unwrap();
+    let job = jobs.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let stream: EventStream = match &job.events {
+        Some(events) => Box::pin(
+            BroadcastStream::new(events.subscribe())
+                .filter_map(|event| event.ok())
+                .map(|event| Ok(event.to_sse())),
+        ),
+        None => Box::pin(tokio_stream::once(Ok(JobEvent::Finished(job.status).to_sse()))),
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
@@ -1 +1,40 @@
+//! HTTP API over the model, served by the `cardiovascular_model_fitting` binary.
+
+pub mod batch;
+pub mod jobs;
 pub mod routes;
+pub mod validation;
+
+use axum::{
+    routing::{get, post},
+    Router,
+};
+use jobs::SharedJobStore;
+
+/// Every endpoint, with jobs and batches run from `jobs`.
+pub fn router(jobs: SharedJobStore) -> Router {
+    // v2 takes named-field targets and parameter overrides; job and batch lookups are shared.
+    let v2 = Router::new()
+        .route("/optimize", post(routes::optimize_v2))
+        .route("/simulate", post(routes::simulate_v2))
+        .route("/pv-loops", post(routes::pv_loops_v2))
+        .route("/jobs", post(jobs::create_job_v2))
+        .route("/jobs/:id", get(jobs::get_job).delete(jobs::cancel_job))
+        .route("/jobs/:id/events", get(jobs::job_events))
+        .route("/batches", post(batch::create_batch_v2))
+        .route("/batches/:id", get(batch::get_batch).delete(batch::cancel_batch))
+        .route("/batches/:id/summary", get(batch::batch_summary));
+
+    Router::new()
+        .route("/optimize", post(routes::optimize))
+        .route("/simulate", post(routes::simulate))
+        .route("/pv-loops", post(routes::pv_loops))
+        .route("/jobs", post(jobs::create_job))
+        .route("/jobs/:id", get(jobs::get_job).delete(jobs::cancel_job))
+        .route("/jobs/:id/events", get(jobs::job_events))
+        .route("/batches", post(batch::create_batch))
+        .route("/batches/:id", get(batch::get_batch).delete(batch::cancel_batch))
+        .route("/batches/:id/summary", get(batch::batch_summary))
+        .nest("/v2", v2)
+        .with_state(jobs)
+}
@@ -1,42 +1,556 @@
-use crate::model::simulation;
-use crate::model::parameters::HemodynamicParams;
+use crate::model::cmaes::CmaesSettings;
+use crate::model::polish::{self, PolishReport, PolishSettings};
+use crate::model::simulation::{
+    self, Algorithm, GaSettings, RunControl, SimulationResult, SimulationSettings, Solver, SteadyState,
+    SteadyStateSettings, StopReason, StoppingCriteria, Condition, Objective, TargetMetric, Waveforms,
+};
+use crate::model::parameters::{CompiledParams, HemodynamicParams, LegacyParamUpdate, ParamOverride, ParamUpdate, ParamUpdates};
+use crate::model::pv_loop::{self, PvLoops};
+use crate::model::waveform::{self, WaveformFit, WaveformTarget};
+use crate::api::jobs::SharedJobStore;
+use crate::api::validation::{self, ValidationErrors};
+use crate::error::{Error, ModelError};
 
 use axum::{
-    extract::Json,
-    response::IntoResponse,
+    extract::{rejection::JsonRejection, Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
-
 use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize)]
-pub struct OptimizationParams {
-    pub target_metrics: Vec<(f64, String, f64)>,
-    pub param_updates: Option<FxHashMap<String, (Option<f64>, Option<(f64, f64)>, Option<bool>)>>,
+/// Body of an optimization request. The v2 API uses named fields; the v1 API sends
+/// the same document with positional tuples (see `OptimizationParamsV1`).
+#[derive(Clone, Deserialize)]
+pub struct OptimizationParams<T = TargetMetric, U = ParamUpdate> {
+    #[serde(default = "Vec::new")]
+    pub target_metrics: Vec<T>,
+    /// Measured pressure beats to match in shape, alongside `target_metrics`.
+    #[serde(default)]
+    pub waveform_targets: Vec<WaveformTarget>,
+    /// Further measurement conditions fitted jointly with the targets above.
+    #[serde(default = "Vec::new")]
+    pub conditions: Vec<ConditionParams<T>>,
+    pub param_updates: Option<FxHashMap<String, U>>,
     pub num_repeats: usize,
+    #[serde(default)]
+    pub solver: Solver,
+    #[serde(default)]
+    pub steady_state: SteadyStateSettings,
+    #[serde(default)]
+    pub algorithm: AlgorithmName,
+    /// Only used when `algorithm` is `ga`.
+    pub optimizer_settings: Option<GaSettings>,
+    /// Only used when `algorithm` is `cmaes`.
+    pub cmaes_settings: Option<CmaesSettings>,
+    /// Refine the optimizer's best result with Nelder–Mead when present.
+    pub polish: Option<PolishSettings>,
+    /// Makes the run reproducible; a random seed is drawn when absent.
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
 }
 
-#[derive(Serialize)]
+/// Targets measured under another condition, e.g. under dobutamine. `overrides`
+/// apply on top of the shared parameters; all other parameters are shared.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionParams<T = TargetMetric> {
+    pub name: String,
+    #[serde(default)]
+    pub overrides: FxHashMap<String, ParamOverride>,
+    #[serde(default = "Vec::new")]
+    pub target_metrics: Vec<T>,
+    #[serde(default)]
+    pub waveform_targets: Vec<WaveformTarget>,
+}
+
+impl<T: Into<TargetMetric>> ConditionParams<T> {
+    pub fn upgrade(self) -> ConditionParams {
+        ConditionParams {
+            name: self.name,
+            overrides: self.overrides,
+            target_metrics: self.target_metrics.into_iter().map(Into::into).collect(),
+            waveform_targets: self.waveform_targets,
+        }
+    }
+}
+
+/// v1 body: `[target, metric, weight]` targets and `[value, range, fitting]` overrides.
+pub type OptimizationParamsV1 = OptimizationParams<(f64, String, f64), LegacyParamUpdate>;
+
+pub fn upgrade_updates<U: Into<ParamUpdate>>(updates: Option<FxHashMap<String, U>>) -> Option<ParamUpdates> {
+    updates.map(|updates| updates.into_iter().map(|(name, update)| (name, update.into())).collect())
+}
+
+impl<T: Into<TargetMetric>, U: Into<ParamUpdate>> OptimizationParams<T, U> {
+    /// Converts a request in either schema to the named-field form the optimizer consumes.
+    pub fn upgrade(self) -> OptimizationParams {
+        OptimizationParams {
+            target_metrics: self.target_metrics.into_iter().map(Into::into).collect(),
+            waveform_targets: self.waveform_targets,
+            conditions: self.conditions.into_iter().map(ConditionParams::upgrade).collect(),
+            param_updates: upgrade_updates(self.param_updates),
+            num_repeats: self.num_repeats,
+            solver: self.solver,
+            steady_state: self.steady_state,
+            algorithm: self.algorithm,
+            optimizer_settings: self.optimizer_settings,
+            cmaes_settings: self.cmaes_settings,
+            polish: self.polish,
+            seed: self.seed,
+            stopping: self.stopping,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgorithmName {
+    #[default]
+    Ga,
+    Cmaes,
+}
+
+#[derive(Clone, Serialize)]
 pub struct OptimizationResult {
     pub best_parameters: HemodynamicParams,
     pub best_fitness: f64,
+    /// Whether the best parameters settle into a periodic steady state.
+    pub steady_state: SteadyState,
+    pub polish: Option<PolishReport>,
+    /// Resolved settings of the optimizer that ran, for reproducing the fit.
+    pub optimizer_settings: Option<GaSettings>,
+    pub cmaes_settings: Option<CmaesSettings>,
+    /// Seed the run used; pass it back to reproduce the fit.
+    pub seed: u64,
+    /// Why the winning repeat stopped, and after how many generations.
+    pub stop_reason: StopReason,
+    pub generations: usize,
+    /// All metrics of the best parameters' steady-state beat, not only the targeted ones.
+    pub metrics: FxHashMap<String, f64>,
+    /// Alignment of each waveform target against the best parameters, in request order.
+    pub waveform_fits: Vec<WaveformFit>,
+    /// The best parameters evaluated under each of the request's `conditions`.
+    pub conditions: Vec<ConditionResult>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ConditionResult {
+    pub name: String,
+    /// All metrics of the condition's steady-state beat, not only the targeted ones.
+    pub metrics: FxHashMap<String, f64>,
+    pub waveform_fits: Vec<WaveformFit>,
+    pub steady_state: SteadyState,
+}
+
+/// Metrics and waveform alignments of `params` under `condition`.
+fn condition_result(
+    condition: &Condition,
+    shared: &HemodynamicParams,
+    settings: &SimulationSettings,
+) -> Result<ConditionResult, ModelError> {
+    let params = condition.params(shared)?;
+    let (sol, steady_state) = simulation::simulate_steady_state(&params, settings)?;
+    let compiled = CompiledParams::try_from(&params)?;
+    Ok(ConditionResult {
+        name: condition.name.clone(),
+        metrics: simulation::calculate_hemodynamic_metrics(&sol, &params)?,
+        waveform_fits: condition
+            .waveforms
+            .iter()
+            .map(|target| waveform::fit_waveform(&sol, target, &compiled))
+            .collect::<Result<_, _>>()?,
+        steady_state,
+    })
 }
 
-pub async fn optimize(Json(params): Json<OptimizationParams>) -> impl IntoResponse {
+/// Number of threads a single optimization may use when it has the machine to itself.
+pub fn available_threads() -> usize {
+    num_cpus::get().max(8)
+}
+
+/// Runs a full optimization request on the calling thread.
+pub fn run_request(
+    params: OptimizationParams,
+    threads: usize,
+    control: &RunControl,
+) -> Result<OptimizationResult, ModelError> {
     let OptimizationParams {
         target_metrics,
+        waveform_targets,
+        conditions,
         param_updates,
         num_repeats,
+        solver,
+        steady_state,
+        algorithm,
+        optimizer_settings,
+        cmaes_settings,
+        polish,
+        seed,
+        stopping,
     } = params;
+    let control = control.clone().with_stopping(stopping);
+    let baseline = Condition {
+        name: "baseline".to_string(),
+        metrics: target_metrics,
+        waveforms: waveform_targets,
+        ..Condition::default()
+    };
+    let has_baseline_targets = !baseline.metrics.is_empty() || !baseline.waveforms.is_empty();
+    let conditions = conditions.into_iter().map(|condition| Condition {
+        name: condition.name,
+        overrides: condition.overrides,
+        metrics: condition.target_metrics,
+        waveforms: condition.waveform_targets,
+    });
+    let objective = Objective {
+        conditions: std::iter::once(baseline).filter(|_| has_baseline_targets).chain(conditions).collect(),
+    };
+    // Drawn seeds stay below 2^53 so JavaScript clients can echo them back exactly.
+    let seed = seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+    let settings = SimulationSettings { solver, steady_state };
+    let algorithm = match algorithm {
+        AlgorithmName::Ga => Algorithm::Genetic(optimizer_settings.unwrap_or_default()),
+        AlgorithmName::Cmaes => Algorithm::Cmaes(cmaes_settings.unwrap_or_default()),
+    };
 
-    let (best_params, best_fitness) = simulation::run_optimization(
-        &target_metrics,
+    let (mut best_params, mut best_fitness, outcome) = simulation::run_optimization(
+        &objective,
         param_updates,
         num_repeats,
-    );
+        &settings,
+        &algorithm,
+        seed,
+        threads,
+        &control,
+    )?;
+
+    let polish = match polish {
+        Some(polish_settings) => {
+            let (polished, report) = polish::nelder_mead(
+                &best_params,
+                best_fitness,
+                &objective,
+                &settings,
+                &polish_settings,
+                &control,
+            )?;
+            best_params = polished;
+            best_fitness = report.fitness_after;
+            Some(report)
+        }
+        None => None,
+    };
+    let mut condition_results = objective
+        .conditions
+        .iter()
+        .map(|condition| condition_result(condition, &best_params, &settings))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    // The baseline condition is the first one when it has targets; otherwise it
+    // still runs once for the steady state and metrics of the best parameters.
+    let baseline = if has_baseline_targets { condition_results.next() } else { None };
+    let baseline = match baseline {
+        Some(baseline) => baseline,
+        None => condition_result(&Condition::default(), &best_params, &settings)?,
+    };
+    let conditions = condition_results.collect();
 
-    Json(OptimizationResult {
+    Ok(OptimizationResult {
         best_parameters: best_params,
         best_fitness,
+        steady_state: baseline.steady_state,
+        polish,
+        optimizer_settings: match algorithm {
+            Algorithm::Genetic(ga) => Some(ga),
+            Algorithm::Cmaes(_) => None,
+        },
+        cmaes_settings: match algorithm {
+            Algorithm::Cmaes(cmaes) => Some(cmaes),
+            Algorithm::Genetic(_) => None,
+        },
+        seed,
+        stop_reason: outcome.stop_reason,
+        generations: outcome.generations,
+        metrics: baseline.metrics,
+        waveform_fits: baseline.waveform_fits,
+        conditions,
     })
-}
\ No newline at end of file
+}
+
+impl OptimizationParams {
+    /// Rejects requests the optimizer cannot run with, listing every offending field.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let has_targets = !self.target_metrics.is_empty()
+            || !self.waveform_targets.is_empty()
+            || self.conditions.iter().any(|c| !c.target_metrics.is_empty() || !c.waveform_targets.is_empty());
+        errors.check(has_targets, "target_metrics", "at least one target metric or waveform target is required");
+        validation::target_metrics(&self.target_metrics, "", &mut errors);
+        validation::waveform_targets(&self.waveform_targets, "", &mut errors);
+        let shared = validation::param_updates(self.param_updates.as_ref(), &mut errors);
+        for (i, condition) in self.conditions.iter().enumerate() {
+            let prefix = format!("conditions[{}].", i);
+            errors.check(!condition.name.is_empty(), format!("{}name", prefix), "must not be empty");
+            errors.check(
+                condition.name.is_empty() || self.conditions[..i].iter().all(|other| other.name != condition.name),
+                format!("{}name", prefix),
+                format!("duplicate condition name '{}'", condition.name),
+            );
+            errors.check(
+                !condition.target_metrics.is_empty() || !condition.waveform_targets.is_empty(),
+                format!("{}target_metrics", prefix),
+                "a condition needs at least one target metric or waveform target",
+            );
+            validation::param_overrides(&condition.overrides, &shared, &prefix, &mut errors);
+            validation::target_metrics(&condition.target_metrics, &prefix, &mut errors);
+            validation::waveform_targets(&condition.waveform_targets, &prefix, &mut errors);
+        }
+        validation::num_repeats(self.num_repeats, &mut errors);
+        validation::solver(&self.solver, &mut errors);
+        validation::steady_state(&self.steady_state, &mut errors);
+        match self.algorithm {
+            AlgorithmName::Ga => validation::ga_settings(&self.optimizer_settings.unwrap_or_default(), &mut errors),
+            AlgorithmName::Cmaes => validation::cmaes_settings(&self.cmaes_settings.unwrap_or_default(), &mut errors),
+        }
+        if let Some(polish) = &self.polish {
+            validation::polish_settings(polish, &mut errors);
+        }
+        validation::stopping(&self.stopping, &mut errors);
+        errors.into_result()
+    }
+}
+
+/// Parses a request body in either schema and validates the upgraded request.
+pub fn parse_optimization<T, U>(
+    payload: Result<Json<OptimizationParams<T, U>>, JsonRejection>,
+) -> Result<OptimizationParams, ValidationErrors>
+where
+    T: Into<TargetMetric>,
+    U: Into<ParamUpdate>,
+{
+    let params = validation::parse_body(payload)?.upgrade();
+    params.validate()?;
+    Ok(params)
+}
+
+/// Runs CPU-bound model work on the blocking pool, so a long simulation does not
+/// stall the async workers, and answers with its result or error.
+async fn respond_blocking<T, E>(work: impl FnOnce() -> Result<T, E> + Send + 'static) -> Response
+where
+    T: Serialize + Send + 'static,
+    E: IntoResponse + Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(Ok(value)) => Json(value).into_response(),
+        Ok(Err(error)) => error.into_response(),
+        Err(err) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": err.to_string() }))).into_response()
+        }
+    }
+}
+
+/// Runs a synchronous fit as one of the store's workers, so it shares the CPU with
+/// jobs and batches instead of competing with them.
+async fn optimize_parsed(store: SharedJobStore, params: Result<OptimizationParams, ValidationErrors>) -> Response {
+    match params {
+        Ok(params) => {
+            let _permit = store.workers.acquire().await.unwrap();
+            let threads = store.threads_per_job;
+            respond_blocking(move || run_request(params, threads, &RunControl::new())).await
+        }
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn optimize(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<OptimizationParamsV1>, JsonRejection>,
+) -> Response {
+    optimize_parsed(store, parse_optimization(payload)).await
+}
+
+pub async fn optimize_v2(
+    State(store): State<SharedJobStore>,
+    payload: Result<Json<OptimizationParams>, JsonRejection>,
+) -> Response {
+    optimize_parsed(store, parse_optimization(payload)).await
+}
+
+#[derive(Deserialize)]
+pub struct SimulateParams<U = ParamUpdate> {
+    pub param_updates: Option<FxHashMap<String, U>>,
+    #[serde(default)]
+    pub solver: Solver,
+    #[serde(default)]
+    pub steady_state: SteadyStateSettings,
+    /// Number of steady-state beats to return.
+    #[serde(default = "default_beats")]
+    pub beats: usize,
+    /// Time window (ms) relative to the first returned beat.
+    pub t_start: Option<f64>,
+    pub t_end: Option<f64>,
+    /// Keep every n-th sample of the 2 ms grid.
+    #[serde(default = "default_decimation")]
+    pub decimation: usize,
+}
+
+impl<U: Into<ParamUpdate>> SimulateParams<U> {
+    pub fn upgrade(self) -> SimulateParams {
+        SimulateParams {
+            param_updates: upgrade_updates(self.param_updates),
+            solver: self.solver,
+            steady_state: self.steady_state,
+            beats: self.beats,
+            t_start: self.t_start,
+            t_end: self.t_end,
+            decimation: self.decimation,
+        }
+    }
+}
+
+fn default_beats() -> usize {
+    1
+}
+
+fn default_decimation() -> usize {
+    1
+}
+
+/// Checks the model inputs shared by `/simulate` and `/pv-loops` and resolves the parameters.
+fn validate_model_inputs(
+    param_updates: Option<&ParamUpdates>,
+    solver: &Solver,
+    steady_state: &SteadyStateSettings,
+    errors: &mut ValidationErrors,
+) -> HemodynamicParams {
+    let params = validation::param_updates(param_updates, errors);
+    validation::solver(solver, errors);
+    validation::steady_state(steady_state, errors);
+    params
+}
+
+pub async fn simulate(payload: Result<Json<SimulateParams<LegacyParamUpdate>>, JsonRejection>) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => respond_blocking(move || run_simulation(params.upgrade())).await,
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn simulate_v2(payload: Result<Json<SimulateParams>, JsonRejection>) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => respond_blocking(move || run_simulation(params)).await,
+        Err(errors) => errors.into_response(),
+    }
+}
+
+/// Validates a simulation request and records its steady-state waveforms.
+pub fn run_simulation(params: SimulateParams) -> Result<Waveforms, Error> {
+    let mut errors = ValidationErrors::new();
+    let model_params =
+        validate_model_inputs(params.param_updates.as_ref(), &params.solver, &params.steady_state, &mut errors);
+    errors.check((1..=100).contains(&params.beats), "beats", "must be between 1 and 100");
+    errors.check(params.decimation >= 1, "decimation", "must be at least 1");
+    for (field, value) in [("t_start", params.t_start), ("t_end", params.t_end)] {
+        errors.check(value.is_none_or(f64::is_finite), field, "must be a finite number");
+    }
+    if let (Some(start), Some(end)) = (params.t_start, params.t_end) {
+        errors.check(start < end, "t_end", "must be greater than t_start");
+    }
+    errors.into_result()?;
+    let settings = SimulationSettings {
+        solver: params.solver,
+        steady_state: params.steady_state,
+    };
+
+    Ok(simulation::simulate_waveforms(
+        &model_params,
+        &settings,
+        params.beats,
+        (params.t_start, params.t_end),
+        params.decimation,
+    )?)
+}
+
+#[derive(Deserialize)]
+pub struct PvLoopParams<U = ParamUpdate> {
+    pub param_updates: Option<FxHashMap<String, U>>,
+    #[serde(default)]
+    pub solver: Solver,
+    #[serde(default)]
+    pub steady_state: SteadyStateSettings,
+}
+
+impl<U: Into<ParamUpdate>> PvLoopParams<U> {
+    pub fn upgrade(self) -> PvLoopParams {
+        PvLoopParams {
+            param_updates: upgrade_updates(self.param_updates),
+            solver: self.solver,
+            steady_state: self.steady_state,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PvLoopResult {
+    #[serde(flatten)]
+    pub loops: PvLoops,
+    pub steady_state: SteadyState,
+}
+
+pub async fn pv_loops(payload: Result<Json<PvLoopParams<LegacyParamUpdate>>, JsonRejection>) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => respond_blocking(move || run_pv_loops(params.upgrade())).await,
+        Err(errors) => errors.into_response(),
+    }
+}
+
+pub async fn pv_loops_v2(payload: Result<Json<PvLoopParams>, JsonRejection>) -> Response {
+    match validation::parse_body(payload) {
+        Ok(params) => respond_blocking(move || run_pv_loops(params)).await,
+        Err(errors) => errors.into_response(),
+    }
+}
+
+/// Validates the model inputs and runs them to steady state.
+fn steady_state_beat(
+    params: &PvLoopParams,
+) -> Result<(HemodynamicParams, SimulationResult, SteadyState), Error> {
+    let mut errors = ValidationErrors::new();
+    let model_params =
+        validate_model_inputs(params.param_updates.as_ref(), &params.solver, &params.steady_state, &mut errors);
+    errors.into_result()?;
+    let settings = SimulationSettings {
+        solver: params.solver,
+        steady_state: params.steady_state,
+    };
+    let (sol, steady_state) = simulation::simulate_steady_state(&model_params, &settings)?;
+    Ok((model_params, sol, steady_state))
+}
+
+/// Validates a PV-loop request and extracts the chamber loops of the steady-state beat.
+pub fn run_pv_loops(params: PvLoopParams) -> Result<PvLoopResult, Error> {
+    let (model_params, sol, steady_state) = steady_state_beat(&params)?;
+    Ok(PvLoopResult {
+        loops: pv_loop::pv_loops(&sol, &model_params)?,
+        steady_state,
+    })
+}
+
+#[derive(Serialize)]
+pub struct MetricsResult {
+    pub metrics: FxHashMap<String, f64>,
+    pub steady_state: SteadyState,
+}
+
+/// Computes every metric in `METRIC_NAMES` for the steady-state beat of the
+/// model inputs of a PV-loop request.
+pub fn run_metrics(params: PvLoopParams) -> Result<MetricsResult, Error> {
+    let (model_params, sol, steady_state) = steady_state_beat(&params)?;
+    Ok(MetricsResult {
+        metrics: simulation::calculate_hemodynamic_metrics(&sol, &model_params)?,
+        steady_state,
+    })
+}
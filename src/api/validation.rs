@@ -0,0 +1,48 @@
+//! Request validation for the HTTP API. Failed checks are answered with
+//! `422 Unprocessable Entity` and the list of offending fields; inputs the model
+//! cannot run with get the same status and a single `error` message.
+
+pub use crate::error::{FieldError, ValidationErrors};
+use crate::error::{Error, ModelError};
+pub use crate::validation::*;
+
+use axum::{
+    extract::{rejection::JsonRejection, Json},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
+}
+
+impl IntoResponse for ModelError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ModelError::Invalid(errors) => return errors.into_response(),
+            ModelError::ThreadPool(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        (status, Json(serde_json::json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        match self {
+            Error::Invalid(errors) => errors.into_response(),
+            Error::Model(error) => error.into_response(),
+        }
+    }
+}
+
+/// Turns a body that failed to deserialize into the same error shape as failed checks.
+pub fn parse_body<T>(payload: Result<Json<T>, JsonRejection>) -> Result<T, ValidationErrors> {
+    payload.map(|Json(value)| value).map_err(|rejection| {
+        let mut errors = ValidationErrors::new();
+        errors.add("body", rejection.body_text());
+        errors
+    })
+}
@@ -0,0 +1,255 @@
+//! Offline front end to the fitting API: reads the same request JSON as the HTTP
+//! endpoints from a file or stdin and writes the response to stdout or a file.
+
+use cardiovascular_model_fitting::api::batch::{self, BatchParams, BatchParamsV1, SummaryRow};
+use cardiovascular_model_fitting::api::routes::{self, OptimizationParams, OptimizationParamsV1, PvLoopParams, SimulateParams};
+use cardiovascular_model_fitting::api::validation::ValidationErrors;
+use cardiovascular_model_fitting::error::{Error, ModelError};
+use cardiovascular_model_fitting::model::parameters::LegacyParamUpdate;
+use cardiovascular_model_fitting::model::simulation::RunControl;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: cvfit <COMMAND> [OPTIONS] [INPUT]
+
+Commands:
+  fit       Fit parameters to an optimization request (as POST /optimize)
+  simulate  Simulate steady-state waveforms (as POST /simulate)
+  metrics   Compute all metrics of the steady-state beat (input as POST /pv-loops)
+  batch     Fit a patient cohort (as POST /batches)
+
+INPUT is a request JSON file; stdin is read when it is missing or \"-\".
+
+Options:
+  -o, --output <PATH>   Write the result to PATH instead of stdout
+      --v2              Read the named-field (v2) request schema
+      --threads <N>     Threads to use (default: all cores)
+      --progress        Report progress on stderr (fit, batch)
+      --summary <PATH>  Also write the cohort summary table, as CSV when PATH
+                        ends in .csv and as JSON otherwise (batch)
+  -h, --help            Print this help";
+
+#[derive(Clone, Copy)]
+enum Command {
+    Fit,
+    Simulate,
+    Metrics,
+    Batch,
+}
+
+struct Args {
+    command: Command,
+    input: Option<String>,
+    output: Option<String>,
+    v2: bool,
+    threads: usize,
+    progress: bool,
+    summary: Option<String>,
+}
+
+enum CliError {
+    Usage(String),
+    Io(String, io::Error),
+    Json(serde_json::Error),
+    Invalid(ValidationErrors),
+    Model(ModelError),
+}
+
+impl From<ValidationErrors> for CliError {
+    fn from(errors: ValidationErrors) -> Self {
+        CliError::Invalid(errors)
+    }
+}
+
+impl From<ModelError> for CliError {
+    fn from(error: ModelError) -> Self {
+        Error::from(error).into()
+    }
+}
+
+impl From<Error> for CliError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Invalid(errors) => CliError::Invalid(errors),
+            Error::Model(error) => CliError::Model(error),
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, CliError> {
+    let command = match args.next().as_deref() {
+        Some("fit") => Command::Fit,
+        Some("simulate") => Command::Simulate,
+        Some("metrics") => Command::Metrics,
+        Some("batch") => Command::Batch,
+        Some("-h" | "--help") => return Ok(None),
+        Some(other) => return Err(CliError::Usage(format!("unknown command '{}'", other))),
+        None => return Err(CliError::Usage("missing command".to_string())),
+    };
+    let mut parsed = Args {
+        command,
+        input: None,
+        output: None,
+        v2: false,
+        threads: num_cpus::get(),
+        progress: false,
+        summary: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| CliError::Usage(format!("{} needs a value", name)));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => parsed.output = Some(value(&arg)?),
+            "--v2" => parsed.v2 = true,
+            "--threads" => {
+                parsed.threads = value(&arg)?
+                    .parse()
+                    .ok()
+                    .filter(|&threads| threads > 0)
+                    .ok_or_else(|| CliError::Usage("--threads must be a positive integer".to_string()))?
+            }
+            "--progress" => parsed.progress = true,
+            "--summary" => parsed.summary = Some(value(&arg)?),
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(CliError::Usage(format!("unknown option '{}'", flag)))
+            }
+            _ if parsed.input.is_some() => return Err(CliError::Usage("more than one INPUT given".to_string())),
+            _ => parsed.input = Some(arg),
+        }
+    }
+    if parsed.summary.is_some() && !matches!(command, Command::Batch) {
+        return Err(CliError::Usage("--summary only applies to batch".to_string()));
+    }
+    Ok(Some(parsed))
+}
+
+fn read_request<T: DeserializeOwned>(args: &Args) -> Result<T, CliError> {
+    let text = match args.input.as_deref() {
+        None | Some("-") => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).map_err(|error| CliError::Io("stdin".to_string(), error))?;
+            text
+        }
+        Some(path) => fs::read_to_string(path).map_err(|error| CliError::Io(path.to_string(), error))?,
+    };
+    serde_json::from_str(&text).map_err(CliError::Json)
+}
+
+fn write_output(path: Option<&str>, contents: &str) -> Result<(), CliError> {
+    match path {
+        Some(path) => fs::write(path, contents).map_err(|error| CliError::Io(path.to_string(), error)),
+        None => writeln!(io::stdout(), "{}", contents).map_err(|error| CliError::Io("stdout".to_string(), error)),
+    }
+}
+
+fn write_json(path: Option<&str>, value: &impl Serialize) -> Result<(), CliError> {
+    write_output(path, &serde_json::to_string_pretty(value).map_err(CliError::Json)?)
+}
+
+fn fit(args: &Args) -> Result<(), CliError> {
+    let params = if args.v2 {
+        read_request::<OptimizationParams>(args)?.upgrade()
+    } else {
+        read_request::<OptimizationParamsV1>(args)?.upgrade()
+    };
+    params.validate()?;
+    let mut control = RunControl::new();
+    if args.progress {
+        control = control.with_progress(|progress| {
+            eprintln!(
+                "repeat {} generation {}/{}: best {:.6}, median {:.6}",
+                progress.repeat + 1,
+                progress.generation,
+                progress.generations,
+                progress.best_fitness,
+                progress.median_fitness
+            )
+        });
+    }
+    write_json(args.output.as_deref(), &routes::run_request(params, args.threads, &control)?)
+}
+
+fn simulate(args: &Args) -> Result<(), CliError> {
+    let params = if args.v2 {
+        read_request::<SimulateParams>(args)?
+    } else {
+        read_request::<SimulateParams<LegacyParamUpdate>>(args)?.upgrade()
+    };
+    write_json(args.output.as_deref(), &routes::run_simulation(params)?)
+}
+
+fn metrics(args: &Args) -> Result<(), CliError> {
+    let params = if args.v2 {
+        read_request::<PvLoopParams>(args)?
+    } else {
+        read_request::<PvLoopParams<LegacyParamUpdate>>(args)?.upgrade()
+    };
+    write_json(args.output.as_deref(), &routes::run_metrics(params)?)
+}
+
+fn run_batch(args: &Args) -> Result<(), CliError> {
+    let requests = if args.v2 {
+        batch::parse_batch(read_request::<BatchParams>(args)?)?
+    } else {
+        batch::parse_batch(read_request::<BatchParamsV1>(args)?)?
+    };
+    let total = requests.len();
+    let patients = batch::run_patients(requests, args.threads, &RunControl::new(), |_, patient| {
+        if args.progress {
+            let status = serde_json::to_value(patient.status).unwrap_or_default();
+            eprintln!("{}: {} (of {} patients)", patient.id, status.as_str().unwrap_or_default(), total);
+        }
+    });
+
+    if let Some(path) = args.summary.as_deref() {
+        let rows: Vec<SummaryRow> = patients.iter().map(SummaryRow::from).collect();
+        if path.ends_with(".csv") {
+            write_output(Some(path), &batch::summary_csv(&rows))?;
+        } else {
+            write_json(Some(path), &rows)?;
+        }
+    }
+    write_json(args.output.as_deref(), &patients)
+}
+
+fn main() -> ExitCode {
+    let outcome = parse_args(std::env::args().skip(1)).and_then(|args| match args {
+        None => write_output(None, USAGE),
+        Some(args) => match args.command {
+            Command::Fit => fit(&args),
+            Command::Simulate => simulate(&args),
+            Command::Metrics => metrics(&args),
+            Command::Batch => run_batch(&args),
+        },
+    });
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            ExitCode::from(2)
+        }
+        Err(CliError::Io(path, error)) => {
+            eprintln!("error: {}: {}", path, error);
+            ExitCode::FAILURE
+        }
+        Err(CliError::Json(error)) => {
+            eprintln!("error: invalid request: {}", error);
+            ExitCode::FAILURE
+        }
+        Err(CliError::Invalid(errors)) => {
+            for error in errors.errors {
+                eprintln!("error: {}: {}", error.field, error.message);
+            }
+            ExitCode::FAILURE
+        }
+        Err(CliError::Model(error)) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Error types shared by the library, the HTTP API and the command-line tool.
+
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Every problem found in an input, by field. Checks keep going after a failure so
+/// all offending fields are reported at once.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Records `message` against `field` unless `ok` holds.
+    pub fn check(&mut self, ok: bool, field: impl Into<String>, message: impl Into<String>) {
+        if !ok {
+            self.add(field, message);
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() { Ok(()) } else { Err(self) }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid input")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Why a simulation or optimization could not produce a result.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ModelError {
+    /// Settings or targets handed to the model failed the `validation` checks.
+    Invalid(ValidationErrors),
+    /// The parameter set has no parameter of this name.
+    UnknownParameter(String),
+    /// A target names a metric the model does not compute.
+    UnknownMetric(String),
+    /// A parameter value the model cannot run with.
+    InvalidParameter { name: String, value: f64 },
+    /// The simulated output holds no sample of its last cardiac cycle.
+    NoCycle,
+    /// An optimization was asked for zero repeats.
+    NoRepeats,
+    /// The simulation blew up to non-finite values or the solver could not make
+    /// progress; for an optimization, every parameter set it tried did.
+    Diverged,
+    /// The worker threads could not be started.
+    ThreadPool(String),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Invalid(errors) => errors.fmt(f),
+            ModelError::UnknownParameter(name) => write!(f, "unknown parameter '{}'", name),
+            ModelError::UnknownMetric(name) => write!(f, "unknown metric '{}'", name),
+            ModelError::InvalidParameter { name, value } => write!(f, "parameter '{}' cannot be {}", name, value),
+            ModelError::NoCycle => write!(f, "the simulation holds no complete cardiac cycle"),
+            ModelError::NoRepeats => write!(f, "an optimization needs at least one repeat"),
+            ModelError::Diverged => write!(f, "the simulation diverged"),
+            ModelError::ThreadPool(message) => write!(f, "could not start worker threads: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<rayon::ThreadPoolBuildError> for ModelError {
+    fn from(error: rayon::ThreadPoolBuildError) -> Self {
+        ModelError::ThreadPool(error.to_string())
+    }
+}
+
+/// Failure of a checked request: either its input was rejected or the run failed.
+#[derive(Debug)]
+pub enum Error {
+    Invalid(ValidationErrors),
+    Model(ModelError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Invalid(errors) => errors.fmt(f),
+            Error::Model(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Invalid(errors) => Some(errors),
+            Error::Model(error) => Some(error),
+        }
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(errors: ValidationErrors) -> Self {
+        Error::Invalid(errors)
+    }
+}
+
+impl From<ModelError> for Error {
+    fn from(error: ModelError) -> Self {
+        match error {
+            ModelError::Invalid(errors) => Error::Invalid(errors),
+            error => Error::Model(error),
+        }
+    }
+}
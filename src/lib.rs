@@ -0,0 +1,28 @@
+//! Lumped-parameter model of the circulation (four chambers, four valves, systemic
+//! and pulmonary beds) with its simulator, hemodynamic metrics and the optimizers
+//! that fit its parameters to measurements.
+//!
+//! The items re-exported here are the stable interface. Inputs are checked with
+//! the functions in [`validation`], which report problems as [`ValidationErrors`].
+//! The HTTP API in `api` and the binaries need the default `server` feature;
+//! depend on the crate with `default-features = false` for the model alone.
+
+pub mod error;
+pub mod model;
+pub mod validation;
+
+#[cfg(feature = "server")]
+pub mod api;
+
+pub use error::{Error, FieldError, ModelError, ValidationErrors};
+pub use model::cmaes::CmaesSettings;
+pub use model::parameters::{HemodynamicParams, ParamOverride, ParamUpdate, ParamUpdates, Parameter};
+pub use model::polish::{nelder_mead, PolishReport, PolishSettings};
+pub use model::pv_loop::{pv_loops, PvLoops};
+pub use model::simulation::{
+    calculate_hemodynamic_metrics, evaluate, run_optimization, simulate_steady_state, simulate_waveforms, Algorithm,
+    Condition, GaSettings, Objective, Progress, RunControl, RunOutcome, SimulationResult, SimulationSettings, Solver,
+    SteadyState, SteadyStateSettings, StopReason, StoppingCriteria, TargetMetric, Waveforms, HEART_RATE_RANGE,
+    METRIC_NAMES,
+};
+pub use model::waveform::{fit_waveform, PressureSignal, WaveformFit, WaveformTarget};
@@ -1,22 +1,42 @@
-mod api;
-mod model;
+use cardiovascular_model_fitting::api::{self, jobs::JobStore};
 
-use axum::{
-    routing::post,
-    Router,
-};
 use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 #[tokio::main]
-async fn main() {
-    let app = Router::new().route("/optimize", post(api::routes::optimize));
+async fn main() -> ExitCode {
+    let max_jobs = std::env::var("MAX_CONCURRENT_JOBS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2);
+    let ttl = std::env::var("JOB_TTL_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map_or(Duration::from_secs(3600), Duration::from_secs);
+    let app = api::router(Arc::new(JobStore::new(max_jobs).with_ttl(ttl)));
 
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let addr = SocketAddr::from(([0, 0, 0, 0], port.parse().unwrap()));
+    let Ok(port) = port.parse() else {
+        eprintln!("error: invalid PORT '{}'", port);
+        return ExitCode::FAILURE;
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("error: cannot listen on {}: {}", addr, err);
+            return ExitCode::FAILURE;
+        }
+    };
     println!("Listening on {}", addr);
 
-    let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+    if let Err(err) = axum::serve(listener, app).await {
+        eprintln!("error: {}", err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
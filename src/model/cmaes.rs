@@ -0,0 +1,265 @@
+//! Covariance matrix adaptation evolution strategy over the fitting parameters.
+//!
+//! Search happens in the unit cube: each fitting parameter is normalised to its
+//! `range`, so a single step size suits all of them.
+
+use crate::error::ModelError;
+use crate::model::parameters::{FittingEncoding, HemodynamicParams};
+use crate::model::simulation::{
+    evaluate, stream_rng, Objective, Progress, RunControl, RunOutcome, SimulationSettings, StopReason,
+};
+use pcg_rand::Pcg32;
+use rand::Rng;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CmaesSettings {
+    /// Offspring per generation; defaults to `4 + 3 ln(n)`.
+    pub population_size: Option<usize>,
+    /// Initial step size in normalised units.
+    #[serde(default = "default_sigma")]
+    pub sigma: f64,
+    #[serde(default = "default_generations")]
+    pub generations: usize,
+}
+
+fn default_sigma() -> f64 {
+    0.3
+}
+
+fn default_generations() -> usize {
+    200
+}
+
+impl Default for CmaesSettings {
+    fn default() -> Self {
+        Self {
+            population_size: None,
+            sigma: default_sigma(),
+            generations: default_generations(),
+        }
+    }
+}
+
+/// Fitness of `x` after clipping to the unit cube, plus a penalty on the clipped distance.
+fn penalized_fitness(
+    encoding: &FittingEncoding,
+    x: &[f64],
+    objective: &Objective,
+    settings: &SimulationSettings,
+) -> Result<f64, ModelError> {
+    let outside: f64 = x.iter().map(|&xi| (xi - xi.clamp(0.0, 1.0)).powi(2)).sum();
+    Ok(evaluate(&encoding.decode(x), objective, settings)? + 1e3 * outside)
+}
+
+fn standard_normal(rng: &mut Pcg32) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Eigen-decomposition of a symmetric matrix by cyclic Jacobi rotations.
+/// Returns the eigenvalues and the eigenvectors as columns.
+fn symmetric_eigen(matrix: &[Vec<f64>]) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = matrix.len();
+    let mut a = matrix.to_vec();
+    let mut v = vec![vec![0.0; n]; n];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for _ in 0..100 {
+        let off: f64 = a
+            .iter()
+            .enumerate()
+            .flat_map(|(p, row)| row[p + 1..].iter())
+            .map(|value| value * value)
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (row_p, row_q) = (a[p].clone(), a[q].clone());
+                for (k, (apk, aqk)) in row_p.iter().zip(&row_q).enumerate() {
+                    a[p][k] = c * apk - s * aqk;
+                    a[q][k] = s * apk + c * aqk;
+                }
+                for row in v.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ((0..n).map(|i| a[i][i]).collect(), v)
+}
+
+/// Minimises `evaluate` with CMA-ES, starting from the centre of the fitting ranges.
+/// Returns the same `(parameters, fitness, outcome)` as the genetic algorithm.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_cmaes(
+    repeat: usize,
+    seed: u64,
+    objective: &Objective,
+    base: &HemodynamicParams,
+    cmaes: &CmaesSettings,
+    threads: usize,
+    settings: &SimulationSettings,
+    control: &RunControl,
+) -> Result<(HemodynamicParams, f64, RunOutcome), ModelError> {
+    let encoding = FittingEncoding::new(base);
+    let n = encoding.len();
+    let mut outcome = RunOutcome {
+        stop_reason: StopReason::MaxGenerations,
+        generations: 0,
+        evaluations: 0,
+    };
+    if n == 0 {
+        outcome.evaluations = 1;
+        return Ok((base.clone(), evaluate(base, objective, settings)?, outcome));
+    }
+
+    let mut rng = stream_rng(seed, &[repeat as u64]);
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+
+    let nf = n as f64;
+    let lambda = cmaes.population_size.unwrap_or(4 + (3.0 * nf.ln()).floor() as usize).max(4);
+    let mu = lambda / 2;
+    let raw_weights: Vec<f64> = (0..mu).map(|i| (mu as f64 + 0.5).ln() - ((i + 1) as f64).ln()).collect();
+    let weight_sum: f64 = raw_weights.iter().sum();
+    let weights: Vec<f64> = raw_weights.iter().map(|w| w / weight_sum).collect();
+    let mueff = 1.0 / weights.iter().map(|w| w * w).sum::<f64>();
+
+    let cc = (4.0 + mueff / nf) / (nf + 4.0 + 2.0 * mueff / nf);
+    let cs = (mueff + 2.0) / (nf + mueff + 5.0);
+    let c1 = 2.0 / ((nf + 1.3).powi(2) + mueff);
+    let cmu = (1.0 - c1).min(2.0 * (mueff - 2.0 + 1.0 / mueff) / ((nf + 2.0).powi(2) + mueff));
+    let damps = 1.0 + 2.0 * (((mueff - 1.0) / (nf + 1.0)).sqrt() - 1.0).max(0.0) + cs;
+    let chi_n = nf.sqrt() * (1.0 - 1.0 / (4.0 * nf) + 1.0 / (21.0 * nf * nf));
+
+    let mut mean = vec![0.5; n];
+    let mut sigma = cmaes.sigma;
+    let mut cov = vec![vec![0.0; n]; n];
+    for (i, row) in cov.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    let mut pc = vec![0.0; n];
+    let mut ps = vec![0.0; n];
+
+    let mut best = (encoding.encode(base), f64::INFINITY);
+    let mut tracker = control.stop_tracker();
+
+    for generation in 0..cmaes.generations {
+        if control.is_cancelled() {
+            outcome.stop_reason = StopReason::Cancelled;
+            break;
+        }
+
+        let (eigenvalues, basis) = symmetric_eigen(&cov);
+        let d: Vec<f64> = eigenvalues.iter().map(|&value| value.max(1e-20).sqrt()).collect();
+
+        let candidates: Vec<Vec<f64>> = (0..lambda)
+            .map(|_| {
+                let z: Vec<f64> = (0..n).map(|_| standard_normal(&mut rng)).collect();
+                (0..n)
+                    .map(|i| mean[i] + sigma * (0..n).map(|j| basis[i][j] * d[j] * z[j]).sum::<f64>())
+                    .collect()
+            })
+            .collect();
+
+        let mut scored: Vec<(Vec<f64>, f64)> = pool.install(|| {
+            candidates
+                .into_par_iter()
+                .map(|x| {
+                    let fitness = penalized_fitness(&encoding, &x, objective, settings)?;
+                    Ok((x, fitness))
+                })
+                .collect::<Result<_, ModelError>>()
+        })?;
+        scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+        outcome.generations = generation + 1;
+        outcome.evaluations += lambda;
+
+        if scored[0].1 < best.1 {
+            best = (scored[0].0.clone(), scored[0].1);
+        }
+
+        let old_mean = mean.clone();
+        mean = (0..n).map(|i| (0..mu).map(|k| weights[k] * scored[k].0[i]).sum()).collect();
+        let y_w: Vec<f64> = (0..n).map(|i| (mean[i] - old_mean[i]) / sigma).collect();
+
+        // C^(-1/2) y_w = B D^-1 B^T y_w
+        let bt_y: Vec<f64> = (0..n).map(|j| (0..n).map(|i| basis[i][j] * y_w[i]).sum::<f64>() / d[j]).collect();
+        let inv_sqrt_y: Vec<f64> = (0..n).map(|i| (0..n).map(|j| basis[i][j] * bt_y[j]).sum()).collect();
+
+        let cs_norm = (cs * (2.0 - cs) * mueff).sqrt();
+        for i in 0..n {
+            ps[i] = (1.0 - cs) * ps[i] + cs_norm * inv_sqrt_y[i];
+        }
+        let ps_norm = ps.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let hsig = ps_norm / (1.0 - (1.0 - cs).powi(2 * (generation as i32 + 1))).sqrt() / chi_n
+            < 1.4 + 2.0 / (nf + 1.0);
+        let hsig = if hsig { 1.0 } else { 0.0 };
+
+        let cc_norm = (cc * (2.0 - cc) * mueff).sqrt();
+        for i in 0..n {
+            pc[i] = (1.0 - cc) * pc[i] + hsig * cc_norm * y_w[i];
+        }
+
+        let steps: Vec<Vec<f64>> = scored
+            .iter()
+            .take(mu)
+            .map(|(x, _)| (0..n).map(|i| (x[i] - old_mean[i]) / sigma).collect())
+            .collect();
+        for i in 0..n {
+            for j in 0..=i {
+                let rank_mu: f64 = (0..mu).map(|k| weights[k] * steps[k][i] * steps[k][j]).sum();
+                let value = (1.0 - c1 - cmu) * cov[i][j]
+                    + c1 * (pc[i] * pc[j] + (1.0 - hsig) * cc * (2.0 - cc) * cov[i][j])
+                    + cmu * rank_mu;
+                cov[i][j] = value;
+                cov[j][i] = value;
+            }
+        }
+
+        sigma *= ((cs / damps) * (ps_norm / chi_n - 1.0)).exp();
+
+        let median_fitness = scored[scored.len() / 2].1;
+        control.report(|| Progress {
+            repeat,
+            generation: generation + 1,
+            generations: cmaes.generations,
+            best_fitness: best.1,
+            median_fitness,
+            mutation_rate: sigma,
+            best_parameters: encoding.decode(&best.0),
+        });
+
+        if let Some(reason) = tracker.check(best.1, outcome.evaluations) {
+            outcome.stop_reason = reason;
+            break;
+        }
+    }
+
+    let best_params = encoding.decode(&best.0);
+    let best_fitness = evaluate(&best_params, objective, settings)?;
+    outcome.evaluations += 1;
+    Ok((best_params, best_fitness, outcome))
+}
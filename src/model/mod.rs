@@ -1,2 +1,6 @@
+pub mod cmaes;
 pub mod parameters;
-pub mod simulation;
\ No newline at end of file
+pub mod polish;
+pub mod pv_loop;
+pub mod simulation;
+pub mod waveform;
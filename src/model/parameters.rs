@@ -1,6 +1,64 @@
+use crate::error::{ModelError, ValidationErrors};
+use crate::validation;
+
 use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
 
+/// Override of a single parameter; absent fields keep the current setting.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamUpdate {
+    pub value: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Whether the optimizer fits the parameter or keeps it fixed.
+    pub fit: Option<bool>,
+}
+
+/// Positional form of a parameter override used by the v1 API: `(value, range, fitting)`.
+pub type LegacyParamUpdate = (Option<f64>, Option<(f64, f64)>, Option<bool>);
+
+impl From<LegacyParamUpdate> for ParamUpdate {
+    fn from((value, range, fit): LegacyParamUpdate) -> Self {
+        Self {
+            value,
+            min: range.map(|(min, _)| min),
+            max: range.map(|(_, max)| max),
+            fit,
+        }
+    }
+}
+
+impl ParamUpdate {
+    /// This update laid over `base`: fields set here win, the rest come from `base`.
+    pub fn over(self, base: ParamUpdate) -> Self {
+        Self {
+            value: self.value.or(base.value),
+            min: self.min.or(base.min),
+            max: self.max.or(base.max),
+            fit: self.fit.or(base.fit),
+        }
+    }
+}
+
+/// Per-parameter overrides from a request.
+pub type ParamUpdates = FxHashMap<String, ParamUpdate>;
+
+/// Change one condition makes to a shared parameter: either a fixed `value` or a
+/// `scale` applied to the shared (possibly fitted) value.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamOverride {
+    pub value: Option<f64>,
+    pub scale: Option<f64>,
+}
+
+impl ParamOverride {
+    pub fn apply(&self, shared: f64) -> f64 {
+        self.value.unwrap_or(shared) * self.scale.unwrap_or(1.0)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Parameter {
     pub value: f64,
@@ -87,18 +145,67 @@ impl HemodynamicParams {
         Self { parameters: params }
     }
     
-    pub fn update(&mut self, name: &str, value: Option<f64>, range: Option<(f64, f64)>, fitting: Option<bool>) {
-        if let Some(param) = self.parameters.get_mut(name) {
-            if let Some(value) = value {
-                param.value = value;
-            }
-            if let Some(range) = range {
-                param.range = range;
-            }
-            if let Some(fitting) = fitting {
-                param.fitting = fitting;
+    /// The default parameter set with `updates` applied, rejecting unknown names and
+    /// values or ranges the model cannot run with.
+    pub fn with_updates(updates: &ParamUpdates) -> Result<Self, ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let params = validation::param_updates(Some(updates), &mut errors);
+        errors.into_result().map(|()| params)
+    }
+
+    /// Current value of the parameter `name`.
+    pub fn value(&self, name: &str) -> Result<f64, ModelError> {
+        self.parameters
+            .get(name)
+            .map(|param| param.value)
+            .ok_or_else(|| ModelError::UnknownParameter(name.to_string()))
+    }
+
+    fn check_known<'a>(&self, mut names: impl Iterator<Item = &'a String>) -> Result<(), ModelError> {
+        match names.find(|name| !self.parameters.contains_key(*name)) {
+            Some(name) => Err(ModelError::UnknownParameter(name.clone())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update(&mut self, name: &str, update: &ParamUpdate) -> Result<(), ModelError> {
+        let param = self
+            .parameters
+            .get_mut(name)
+            .ok_or_else(|| ModelError::UnknownParameter(name.to_string()))?;
+        if let Some(value) = update.value {
+            param.value = value;
+        }
+        if let Some(min) = update.min {
+            param.range.0 = min;
+        }
+        if let Some(max) = update.max {
+            param.range.1 = max;
+        }
+        if let Some(fit) = update.fit {
+            param.fitting = fit;
+        }
+        Ok(())
+    }
+
+    /// Applies every update, or none if any names an unknown parameter.
+    pub fn apply_updates(&mut self, updates: &ParamUpdates) -> Result<(), ModelError> {
+        self.check_known(updates.keys())?;
+        for (name, update) in updates {
+            self.update(name, update)?;
+        }
+        Ok(())
+    }
+
+    /// Applies every override, or none if any names an unknown parameter.
+    pub fn apply_overrides(&mut self, overrides: &FxHashMap<String, ParamOverride>) -> Result<(), ModelError> {
+        self.check_known(overrides.keys())?;
+        for (name, change) in overrides {
+            if let Some(param) = self.parameters.get_mut(name) {
+                param.value = change.apply(param.value);
             }
         }
+        Ok(())
     }
 }
 
@@ -106,4 +213,151 @@ impl Default for HemodynamicParams {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+/// Maps the fitting parameters of a base parameter set to points in the unit cube,
+/// each coordinate spanning that parameter's `range`. Decoding clips to the cube.
+pub(crate) struct FittingEncoding {
+    base: HemodynamicParams,
+    names: Vec<String>,
+}
+
+impl FittingEncoding {
+    pub fn new(base: &HemodynamicParams) -> Self {
+        let mut names: Vec<String> = base
+            .parameters
+            .iter()
+            .filter(|(_, param)| param.fitting)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        Self { base: base.clone(), names }
+    }
+
+    /// Number of fitting parameters.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn encode(&self, params: &HemodynamicParams) -> Vec<f64> {
+        self.names
+            .iter()
+            .map(|name| {
+                let param = &params.parameters[name];
+                let (lo, hi) = param.range;
+                if hi > lo { (param.value - lo) / (hi - lo) } else { 0.5 }
+            })
+            .collect()
+    }
+
+    pub fn decode(&self, x: &[f64]) -> HemodynamicParams {
+        let mut params = self.base.clone();
+        for (name, &xi) in self.names.iter().zip(x) {
+            let param = params.parameters.get_mut(name).unwrap();
+            let (lo, hi) = param.range;
+            param.value = lo + xi.clamp(0.0, 1.0) * (hi - lo);
+        }
+        params
+    }
+}
+
+/// Time-varying elastance parameters of one chamber.
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy)]
+pub struct Chamber {
+    pub Ees: f64,
+    pub V0: f64,
+    pub alpha: f64,
+    pub beta: f64,
+    pub Tmax: f64,
+    pub tau: f64,
+    pub AV_delay: f64,
+}
+
+impl Chamber {
+    /// `names` lists the chamber's Ees, V0, alpha, beta, Tmax, tau and AV_delay keys.
+    #[allow(non_snake_case)]
+    fn resolve(params: &HemodynamicParams, names: [&str; 7]) -> Result<Self, ModelError> {
+        let [Ees, V0, alpha, beta, Tmax, tau, AV_delay] = names.map(|name| params.value(name));
+        Ok(Self { Ees: Ees?, V0: V0?, alpha: alpha?, beta: beta?, Tmax: Tmax?, tau: tau?, AV_delay: AV_delay? })
+    }
+}
+
+/// Parameter values resolved out of the name-keyed map once per simulation, so the
+/// right-hand side reads plain fields instead of hashing parameter names.
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy)]
+pub struct CompiledParams {
+    pub LV: Chamber,
+    pub LA: Chamber,
+    pub RV: Chamber,
+    pub RA: Chamber,
+    pub HR: f64,
+    pub Rcs: f64,
+    pub Rcp: f64,
+    pub Ras: f64,
+    pub Rvs: f64,
+    pub Rap: f64,
+    pub Rvp: f64,
+    pub Cas: f64,
+    pub Cvs: f64,
+    pub Cap: f64,
+    pub Cvp: f64,
+    pub Ras_prox: f64,
+    pub Rap_prox: f64,
+    pub Cas_prox: f64,
+    pub Cap_prox: f64,
+    pub Rda: f64,
+    pub Cda: f64,
+    pub Rmv: f64,
+    pub Rmvs: f64,
+    pub Rmvr: f64,
+    pub Rtv: f64,
+    pub Rtvs: f64,
+    pub Rtvr: f64,
+    pub Ravs: f64,
+    pub Ravr: f64,
+    pub Rpvs: f64,
+    pub Rpvr: f64,
+}
+
+impl TryFrom<&HemodynamicParams> for CompiledParams {
+    type Error = ModelError;
+
+    fn try_from(params: &HemodynamicParams) -> Result<Self, ModelError> {
+        let get = |name: &str| params.value(name);
+        Ok(Self {
+            LV: Chamber::resolve(params, ["LV_Ees", "LV_V0", "LV_alpha", "LV_beta", "LV_Tmax", "LV_tau", "LV_AV_delay"])?,
+            LA: Chamber::resolve(params, ["LA_Ees", "LA_V0", "LA_alpha", "LA_beta", "LA_Tmax", "LA_tau", "LA_AV_delay"])?,
+            RV: Chamber::resolve(params, ["RV_Ees", "RV_V0", "RV_alpha", "RV_beta", "RV_Tmax", "RV_tau", "RV_AV_delay"])?,
+            RA: Chamber::resolve(params, ["RA_Ees", "RA_V0", "RA_alpha", "RA_beta", "RA_Tmax", "RA_tau", "RA_AV_delay"])?,
+            HR: get("HR")?,
+            Rcs: get("Rcs")?,
+            Rcp: get("Rcp")?,
+            Ras: get("Ras")?,
+            Rvs: get("Rvs")?,
+            Rap: get("Rap")?,
+            Rvp: get("Rvp")?,
+            Cas: get("Cas")?,
+            Cvs: get("Cvs")?,
+            Cap: get("Cap")?,
+            Cvp: get("Cvp")?,
+            Ras_prox: get("Ras_prox")?,
+            Rap_prox: get("Rap_prox")?,
+            Cas_prox: get("Cas_prox")?,
+            Cap_prox: get("Cap_prox")?,
+            Rda: get("Rda")?,
+            Cda: get("Cda")?,
+            Rmv: get("Rmv")?,
+            Rmvs: get("Rmvs")?,
+            Rmvr: get("Rmvr")?,
+            Rtv: get("Rtv")?,
+            Rtvs: get("Rtvs")?,
+            Rtvr: get("Rtvr")?,
+            Ravs: get("Ravs")?,
+            Ravr: get("Ravr")?,
+            Rpvs: get("Rpvs")?,
+            Rpvr: get("Rpvr")?,
+        })
+    }
+}
@@ -0,0 +1,169 @@
+//! Bounded Nelder–Mead refinement of an optimizer's best parameter set.
+
+use crate::error::ModelError;
+use crate::model::parameters::{FittingEncoding, HemodynamicParams};
+use crate::model::simulation::{evaluate, Objective, RunControl, SimulationSettings};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PolishSettings {
+    /// Budget of extra `evaluate` calls; never exceeded.
+    #[serde(default = "default_max_evaluations")]
+    pub max_evaluations: usize,
+    /// Edge length of the initial simplex, in units of each parameter's range.
+    #[serde(default = "default_initial_step")]
+    pub initial_step: f64,
+    /// Stop once the fitness spread across the simplex falls below this.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+}
+
+fn default_max_evaluations() -> usize {
+    500
+}
+
+fn default_initial_step() -> f64 {
+    0.05
+}
+
+fn default_tolerance() -> f64 {
+    1e-8
+}
+
+impl Default for PolishSettings {
+    fn default() -> Self {
+        Self {
+            max_evaluations: default_max_evaluations(),
+            initial_step: default_initial_step(),
+            tolerance: default_tolerance(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PolishReport {
+    pub fitness_before: f64,
+    pub fitness_after: f64,
+    pub evaluations: usize,
+}
+
+/// The objective as a function of a point in the unit cube, counting evaluations.
+struct UnitObjective<'a> {
+    encoding: FittingEncoding,
+    target: &'a Objective,
+    settings: &'a SimulationSettings,
+    evaluations: usize,
+    max_evaluations: usize,
+}
+
+impl UnitObjective<'_> {
+    /// Fitness at `x`, or `None` once the evaluation budget is spent.
+    fn eval(&mut self, x: &[f64]) -> Result<Option<f64>, ModelError> {
+        if self.evaluations >= self.max_evaluations {
+            return Ok(None);
+        }
+        self.evaluations += 1;
+        evaluate(&self.encoding.decode(x), self.target, self.settings).map(Some)
+    }
+}
+
+fn clamp_unit(x: Vec<f64>) -> Vec<f64> {
+    x.into_iter().map(|xi| xi.clamp(0.0, 1.0)).collect()
+}
+
+/// `a + t * (b - a)`, kept inside the unit cube.
+fn along(a: &[f64], b: &[f64], t: f64) -> Vec<f64> {
+    clamp_unit(a.iter().zip(b).map(|(ai, bi)| ai + t * (bi - ai)).collect())
+}
+
+/// Runs Nelder–Mead from `best` over its fitting parameters, normalised to their
+/// ranges and clipped to them. Never returns a worse set than `best`.
+pub fn nelder_mead(
+    best: &HemodynamicParams,
+    best_fitness: f64,
+    objective: &Objective,
+    settings: &SimulationSettings,
+    polish: &PolishSettings,
+    control: &RunControl,
+) -> Result<(HemodynamicParams, PolishReport), ModelError> {
+    let mut objective = UnitObjective {
+        encoding: FittingEncoding::new(best),
+        target: objective,
+        settings,
+        evaluations: 0,
+        max_evaluations: polish.max_evaluations,
+    };
+    let n = objective.encoding.len();
+    let x0 = clamp_unit(objective.encoding.encode(best));
+
+    let mut simplex = vec![(x0.clone(), best_fitness)];
+    for i in 0..n {
+        let mut x = x0.clone();
+        x[i] = if x[i] + polish.initial_step <= 1.0 { x[i] + polish.initial_step } else { x[i] - polish.initial_step };
+        let Some(fitness) = objective.eval(&x)? else {
+            break;
+        };
+        simplex.push((x, fitness));
+    }
+
+    while simplex.len() == n + 1 && n > 0 && objective.evaluations < polish.max_evaluations && !control.is_cancelled() {
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if simplex[n].1 - simplex[0].1 < polish.tolerance {
+            break;
+        }
+
+        let mut centroid = vec![0.0; n];
+        for (x, _) in &simplex[..n] {
+            for (c, xi) in centroid.iter_mut().zip(x) {
+                *c += xi / n as f64;
+            }
+        }
+        let worst = simplex[n].clone();
+
+        let reflected = along(&centroid, &worst.0, -1.0);
+        let Some(f_reflected) = objective.eval(&reflected)? else {
+            break;
+        };
+
+        if f_reflected < simplex[0].1 {
+            let expanded = along(&centroid, &worst.0, -2.0);
+            simplex[n] = match objective.eval(&expanded)? {
+                Some(f_expanded) if f_expanded < f_reflected => (expanded, f_expanded),
+                _ => (reflected, f_reflected),
+            };
+        } else if f_reflected < simplex[n - 1].1 {
+            simplex[n] = (reflected, f_reflected);
+        } else {
+            let (toward, f_toward) = if f_reflected < worst.1 { (&reflected, f_reflected) } else { (&worst.0, worst.1) };
+            let contracted = along(&centroid, toward, 0.5);
+            let Some(f_contracted) = objective.eval(&contracted)? else {
+                break;
+            };
+            if f_contracted < f_toward {
+                simplex[n] = (contracted, f_contracted);
+            } else {
+                let best_vertex = simplex[0].0.clone();
+                for vertex in simplex.iter_mut().skip(1) {
+                    let shrunk = along(&best_vertex, &vertex.0, 0.5);
+                    let Some(fitness) = objective.eval(&shrunk)? else {
+                        break;
+                    };
+                    *vertex = (shrunk, fitness);
+                }
+            }
+        }
+    }
+
+    let (x_best, fitness_after) = simplex
+        .into_iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((x0, best_fitness));
+    let polished = if fitness_after < best_fitness { objective.encoding.decode(&x_best) } else { best.clone() };
+
+    let report = PolishReport {
+        fitness_before: best_fitness,
+        fitness_after: fitness_after.min(best_fitness),
+        evaluations: objective.evaluations,
+    };
+    Ok((polished, report))
+}
@@ -0,0 +1,84 @@
+#![allow(non_snake_case)]
+
+use crate::error::ModelError;
+use crate::model::parameters::{Chamber, CompiledParams, HemodynamicParams};
+use crate::model::simulation::{activation_onset_distance, chamber_pressure, e, SimulationResult};
+use serde::Serialize;
+
+/// A single point on a pressure–volume loop.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PvPoint {
+    pub t: f64,
+    pub volume: f64,
+    pub pressure: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PvLoop {
+    pub volume: Vec<f64>,
+    pub pressure: Vec<f64>,
+    /// Sample at peak activation of the elastance function.
+    pub end_systole: PvPoint,
+    /// Sample at the onset of activation, i.e. the end of the filling phase.
+    pub end_diastole: PvPoint,
+}
+
+/// Pressure–volume loops of the last cycle for all four chambers.
+#[derive(Debug, Serialize)]
+pub struct PvLoops {
+    pub t: Vec<f64>,
+    pub LV: PvLoop,
+    pub RV: PvLoop,
+    pub LA: PvLoop,
+    pub RA: PvLoop,
+}
+
+fn chamber_loop(t: &[f64], volumes: &[f64], chamber: &Chamber, HR: f64) -> PvLoop {
+    let pressure: Vec<f64> = t
+        .iter()
+        .zip(volumes)
+        .map(|(&time, &volume)| chamber_pressure(volume, time, chamber, HR))
+        .collect();
+
+    let activation = |time: f64| e(time - chamber.AV_delay, chamber.Tmax, chamber.tau, HR);
+    let onset_distance = |time: f64| activation_onset_distance(time, chamber, HR);
+    let es = (0..t.len())
+        .max_by(|&a, &b| activation(t[a]).total_cmp(&activation(t[b])))
+        .unwrap_or(0);
+    let ed = (0..t.len())
+        .min_by(|&a, &b| onset_distance(t[a]).total_cmp(&onset_distance(t[b])))
+        .unwrap_or(0);
+    let point = |i: usize| PvPoint { t: t[i], volume: volumes[i], pressure: pressure[i] };
+
+    PvLoop {
+        end_systole: point(es),
+        end_diastole: point(ed),
+        volume: volumes.to_vec(),
+        pressure,
+    }
+}
+
+/// Extracts the last cycle of `sol` as pressure–volume loops, using the
+/// time-varying elastance of each chamber to mark end-systole and end-diastole.
+pub fn pv_loops(sol: &SimulationResult, params: &HemodynamicParams) -> Result<PvLoops, ModelError> {
+    let p = CompiledParams::try_from(params)?;
+    let period = 60000.0 / p.HR;
+    let end_time = sol.t().last().copied().unwrap_or(0.0);
+
+    let (t, y): (Vec<f64>, Vec<[f64; 11]>) = sol
+        .t()
+        .iter()
+        .zip(sol.y())
+        .filter(|(&time, _)| time >= end_time - period)
+        .map(|(&time, state)| (time, *state))
+        .unzip();
+    let volumes = |index: usize| y.iter().map(|state| state[index]).collect::<Vec<_>>();
+
+    Ok(PvLoops {
+        LV: chamber_loop(&t, &volumes(4), &p.LV, p.HR),
+        LA: chamber_loop(&t, &volumes(5), &p.LA, p.HR),
+        RV: chamber_loop(&t, &volumes(6), &p.RV, p.HR),
+        RA: chamber_loop(&t, &volumes(7), &p.RA, p.HR),
+        t,
+    })
+}
@@ -1,16 +1,24 @@
-use crate::model::parameters::HemodynamicParams;
+#![allow(non_snake_case)]
+
+use crate::error::{ModelError, ValidationErrors};
+use crate::model::cmaes::{self, CmaesSettings};
+use crate::model::waveform::{self, WaveformTarget};
+use crate::model::parameters::{Chamber, CompiledParams, HemodynamicParams, ParamOverride, ParamUpdates};
+use crate::validation;
 use rayon::prelude::*;
 use std::f64::consts::PI;
-use std::env;
-use num_cpus;
 use rayon::ThreadPoolBuilder;
 use fxhash::FxHashMap;
 use pcg_rand::Pcg32;
 use rand::SeedableRng;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[inline]
-fn e(t: f64, Tmax: f64, tau: f64, HR: f64) -> f64 {
+pub(crate) fn e(t: f64, Tmax: f64, tau: f64, HR: f64) -> f64 {
     let t_ = t % (60000.0 / HR);
     let base = (-(60000.0 / HR - 3.0 * Tmax / 2.0) / tau).exp() / 2.0;
     if t_ < Tmax {
@@ -23,6 +31,7 @@ fn e(t: f64, Tmax: f64, tau: f64, HR: f64) -> f64 {
 }
 
 #[inline]
+#[allow(clippy::too_many_arguments)]
 fn P(V: f64, t: f64, Ees: f64, V0: f64, alpha: f64, beta: f64, Tmax: f64, tau: f64, AV_delay: f64, HR: f64) -> f64 {
     let x = alpha * (V - V0).clamp(-700.0, 700.0);
     let Ped = beta * (x.exp() - 1.0);
@@ -30,90 +39,141 @@ fn P(V: f64, t: f64, Ees: f64, V0: f64, alpha: f64, beta: f64, Tmax: f64, tau: f
     Ped + e(t - AV_delay, Tmax, tau, HR) * (Pes - Ped)
 }
 
+/// Regurgitant resistance that marks a competent valve: backflow is then only the
+/// leak through the open resistance and is not reported as regurgitation.
+const COMPETENT_VALVE_RESISTANCE: f64 = 100000.0;
+
 #[inline]
 fn calculate_valve_flow(grad: f64, R_open: f64, R_open_var: f64, R_close_var: f64) -> f64 {
     let (r, v) = if grad > 0.0 { (R_open, R_open_var) } else { (R_open + R_close_var, R_close_var) };
-    if v == 0.0 || (grad < 0.0 && R_close_var == 100000.0) {
+    if v == 0.0 || (grad < 0.0 && R_close_var == COMPETENT_VALVE_RESISTANCE) {
         grad / r
     } else {
-        let sign = if grad > 0.0 { -1.0 } else { 1.0 };
-        (sign * r + (r.powi(2) + sign * 4.0 * v * grad).max(0.0).sqrt()) / (2.0 * v)
+        // Solve |grad| = r·|I| + v·I² for the flow, which carries the sign of grad.
+        grad.signum() * (-r + (r.powi(2) + 4.0 * v * grad.abs()).sqrt()) / (2.0 * v)
     }
 }
 
 #[inline]
-fn calculate_pressures_and_flows(
-    t: f64, state: &[f64; 11], params: &HemodynamicParams
-) -> ([f64; 6], [f64; 11]) {
+pub(crate) fn chamber_pressure(V: f64, t: f64, c: &Chamber, HR: f64) -> f64 {
+    P(V, t, c.Ees, c.V0, c.alpha, c.beta, c.Tmax, c.tau, c.AV_delay, HR)
+}
+
+/// Time (ms) from `t` to the nearest onset of the chamber's activation, which
+/// marks end-diastole.
+pub(crate) fn activation_onset_distance(t: f64, c: &Chamber, HR: f64) -> f64 {
+    let period = 60000.0 / HR;
+    let phase = (t - c.AV_delay).rem_euclid(period);
+    phase.min(period - phase)
+}
+
+#[inline]
+/// Returns `[Plv, Pla, Prv, Pra, Iasp, Iapp, Imv, Itv]` and the state derivatives.
+pub fn calculate_pressures_and_flows(
+    t: f64, state: &[f64; 11], p: &CompiledParams
+) -> ([f64; 8], [f64; 11]) {
     let [Qvs, Qas, Qap, Qvp, Qlv, Qla, Qrv, Qra, Qas_prox, Qda, Qap_prox] = *state;
 
-    let Plv = P(
-        Qlv, t, params.parameters["LV_Ees"].value, params.parameters["LV_V0"].value, params.parameters["LV_alpha"].value, 
-        params.parameters["LV_beta"].value, params.parameters["LV_Tmax"].value, params.parameters["LV_tau"].value, 
-        params.parameters["LV_AV_delay"].value, params.parameters["HR"].value
-    );
-    let Pla = P(
-        Qla, t, params.parameters["LA_Ees"].value, params.parameters["LA_V0"].value, params.parameters["LA_alpha"].value, 
-        params.parameters["LA_beta"].value, params.parameters["LA_Tmax"].value, params.parameters["LA_tau"].value, 
-        params.parameters["LA_AV_delay"].value, params.parameters["HR"].value
-    );
-    let Prv = P(
-        Qrv, t, params.parameters["RV_Ees"].value, params.parameters["RV_V0"].value, params.parameters["RV_alpha"].value, 
-        params.parameters["RV_beta"].value, params.parameters["RV_Tmax"].value, params.parameters["RV_tau"].value, 
-        params.parameters["RV_AV_delay"].value, params.parameters["HR"].value
-    );
-    let Pra = P(
-        Qra, t, params.parameters["RA_Ees"].value, params.parameters["RA_V0"].value, params.parameters["RA_alpha"].value, 
-        params.parameters["RA_beta"].value, params.parameters["RA_Tmax"].value, params.parameters["RA_tau"].value, 
-        params.parameters["RA_AV_delay"].value, params.parameters["HR"].value
-    );
-
-    let Ida = (Qas_prox / params.parameters["Cas_prox"].value - Qda / params.parameters["Cda"].value) / params.parameters["Rda"].value;
-    let Ias = (Qda / params.parameters["Cda"].value - Qas / params.parameters["Cas"].value) / params.parameters["Ras"].value;
-    let Ics = (Qas / params.parameters["Cas"].value - Qvs / params.parameters["Cvs"].value) / params.parameters["Rcs"].value;
-    let Ivs = (Qvs / params.parameters["Cvs"].value - Pra) / params.parameters["Rvs"].value;
-
-    let Ivp = (Qvp / params.parameters["Cvp"].value - Pla) / params.parameters["Rvp"].value;
-    let Iap = (Qap / params.parameters["Cap"].value - Qvp / params.parameters["Cvp"].value) / params.parameters["Rap"].value;
-    let Icp = (Qap_prox / params.parameters["Cap_prox"].value - Qap / params.parameters["Cap"].value) / params.parameters["Rcp"].value;
-
-    let Itv = calculate_valve_flow(Pra - Prv, params.parameters["Rtv"].value, params.parameters["Rtvs"].value, params.parameters["Rtvr"].value);
-    let Imv = calculate_valve_flow(Pla - Plv, params.parameters["Rmv"].value, params.parameters["Rmvs"].value, params.parameters["Rmvr"].value);
-    let Iasp = calculate_valve_flow(Plv - Qas_prox / params.parameters["Cas_prox"].value, params.parameters["Ras_prox"].value, params.parameters["Ravs"].value, params.parameters["Ravr"].value);
-    let Iapp = calculate_valve_flow(Prv - Qap_prox / params.parameters["Cap_prox"].value, params.parameters["Rap_prox"].value, params.parameters["Rpvs"].value, params.parameters["Rpvr"].value);
-
-    ([Plv, Pla, Prv, Pra, Iasp, Iapp], [
+    let Plv = chamber_pressure(Qlv, t, &p.LV, p.HR);
+    let Pla = chamber_pressure(Qla, t, &p.LA, p.HR);
+    let Prv = chamber_pressure(Qrv, t, &p.RV, p.HR);
+    let Pra = chamber_pressure(Qra, t, &p.RA, p.HR);
+
+    let Ida = (Qas_prox / p.Cas_prox - Qda / p.Cda) / p.Rda;
+    let Ias = (Qda / p.Cda - Qas / p.Cas) / p.Ras;
+    let Ics = (Qas / p.Cas - Qvs / p.Cvs) / p.Rcs;
+    let Ivs = (Qvs / p.Cvs - Pra) / p.Rvs;
+
+    let Ivp = (Qvp / p.Cvp - Pla) / p.Rvp;
+    let Iap = (Qap / p.Cap - Qvp / p.Cvp) / p.Rap;
+    let Icp = (Qap_prox / p.Cap_prox - Qap / p.Cap) / p.Rcp;
+
+    let Itv = calculate_valve_flow(Pra - Prv, p.Rtv, p.Rtvs, p.Rtvr);
+    let Imv = calculate_valve_flow(Pla - Plv, p.Rmv, p.Rmvs, p.Rmvr);
+    let Iasp = calculate_valve_flow(Plv - Qas_prox / p.Cas_prox, p.Ras_prox, p.Ravs, p.Ravr);
+    let Iapp = calculate_valve_flow(Prv - Qap_prox / p.Cap_prox, p.Rap_prox, p.Rpvs, p.Rpvr);
+
+    ([Plv, Pla, Prv, Pra, Iasp, Iapp, Imv, Itv], [
         Ics - Ivs, Ias - Ics, Icp - Iap, Iap - Ivp, Imv - Iasp, Ivp - Imv, 
         Itv - Iapp, Ivs - Itv, Iasp - Ida, Ida - Ias, Iapp - Icp,
     ])
 }
 
 #[inline]
-fn pv_func(t: f64, state: &[f64; 11], params: &HemodynamicParams) -> [f64; 11] {
+pub fn pv_func(t: f64, state: &[f64; 11], params: &CompiledParams) -> [f64; 11] {
     let (_, flows) = calculate_pressures_and_flows(t, state, params);
     flows
 }
 
+/// Numerical integrator used to advance the circulation model.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Solver {
+    /// Classic fixed-step RK4 on the output grid.
+    #[default]
+    Rk4,
+    /// Embedded Dormand–Prince 5(4) with step-size control and dense output.
+    Dopri5 {
+        #[serde(default = "default_atol")]
+        atol: f64,
+        #[serde(default = "default_rtol")]
+        rtol: f64,
+        /// Upper bound on the internal step (ms).
+        #[serde(default = "default_max_step")]
+        max_step: f64,
+    },
+}
+
+fn default_atol() -> f64 {
+    1e-6
+}
+
+fn default_rtol() -> f64 {
+    1e-6
+}
+
+fn default_max_step() -> f64 {
+    10.0
+}
+
+/// Names of the state variables, in the order used by `SimulationResult::y`.
+pub const STATE_NAMES: [&str; 11] = [
+    "Qvs", "Qas", "Qap", "Qvp", "Qlv", "Qla", "Qrv", "Qra", "Qas_prox", "Qda", "Qap_prox",
+];
+
 #[derive(Debug)]
 pub struct SimulationResult {
-    t: [f64; 5001],
-    y: [[f64; 11]; 5001],
+    t: Vec<f64>,
+    y: Vec<[f64; 11]>,
 }
 
-pub fn runge_kutta_4<F>(
+impl SimulationResult {
+    /// Output times (ms).
+    pub fn t(&self) -> &[f64] {
+        &self.t
+    }
+
+    /// States at each output time, ordered as `STATE_NAMES`.
+    pub fn y(&self) -> &[[f64; 11]] {
+        &self.y
+    }
+}
+
+pub(crate) fn runge_kutta_4<F>(
   mut f: F,
   y0: [f64; 11],
-  t: &[f64; 5001],
-  params: &HemodynamicParams,
+  t: &[f64],
+  params: &CompiledParams,
 ) -> SimulationResult
 where
-  F: FnMut(f64, &[f64; 11], &HemodynamicParams) -> [f64; 11] + Sync ,
+  F: FnMut(f64, &[f64; 11], &CompiledParams) -> [f64; 11] + Sync ,
 {
-  let n = t.len().min(5001); 
-  let mut y = [[0.0; 11]; 5001];
-  let mut t_result = [0.0; 5001];
+  let n = t.len();
+  let mut y = vec![[0.0; 11]; n];
+  if n == 0 {
+      return SimulationResult { t: Vec::new(), y };
+  }
   y[0] = y0;
-  t_result[..n].copy_from_slice(&t[..n]);
 
   for i in 1..n {
       let dt = t[i] - t[i - 1];
@@ -141,33 +201,558 @@ where
   }
 
   SimulationResult {
-      t: t_result,
+      t: t.to_vec(),
+      y,
+  }
+}
+
+// Dormand–Prince 5(4) tableau, with the dense-output weights from Hairer & Wanner.
+const DP_C: [f64; 7] = [0.0, 1.0 / 5.0, 3.0 / 10.0, 4.0 / 5.0, 8.0 / 9.0, 1.0, 1.0];
+const DP_A: [[f64; 6]; 7] = [
+  [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+  [1.0 / 5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+  [3.0 / 40.0, 9.0 / 40.0, 0.0, 0.0, 0.0, 0.0],
+  [44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0, 0.0, 0.0, 0.0],
+  [19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0, 0.0, 0.0],
+  [9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0, 0.0],
+  [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0],
+];
+const DP_E: [f64; 7] = [
+  71.0 / 57600.0, 0.0, -71.0 / 16695.0, 71.0 / 1920.0, -17253.0 / 339200.0, 22.0 / 525.0, -1.0 / 40.0,
+];
+const DP_D: [f64; 7] = [
+  -12715105075.0 / 11282082432.0, 0.0, 87487479700.0 / 32700410799.0, -10690763975.0 / 1880347072.0,
+  701980252875.0 / 199316789632.0, -1453857185.0 / 822651844.0, 69997945.0 / 29380423.0,
+];
+
+/// Attempted steps after which `dormand_prince` gives up on a grid.
+const DP_MAX_STEPS: usize = 100_000;
+
+/// Adaptive Dormand–Prince 5(4) integration from `t[0]` to the last grid point.
+///
+/// The solver picks its own steps; the solution is resampled onto `t` with the
+/// fourth-order continuous extension, so the result lines up with `runge_kutta_4`.
+/// Fails with `Diverged` when the state stops being finite, even at the smallest
+/// step, or when the grid takes more than `DP_MAX_STEPS` steps.
+pub(crate) fn dormand_prince<F>(
+  mut f: F,
+  y0: [f64; 11],
+  t: &[f64],
+  params: &CompiledParams,
+  atol: f64,
+  rtol: f64,
+  max_step: f64,
+) -> Result<SimulationResult, ModelError>
+where
+  F: FnMut(f64, &[f64; 11], &CompiledParams) -> [f64; 11] + Sync ,
+{
+  let n = t.len();
+  let mut y = vec![[0.0; 11]; n];
+  if n == 0 {
+      return Ok(SimulationResult { t: Vec::new(), y });
+  }
+  y[0] = y0;
+
+  let t_end = t[n - 1];
+  let min_step = 1e-6;
+  let mut h = if n > 1 { (t[1] - t[0]).min(max_step) } else { max_step };
+  let mut t_cur = t[0];
+  let mut y_cur = y0;
+  let mut k = [[0.0; 11]; 7];
+  k[0] = f(t_cur, &y_cur, params);
+  let mut next_out = 1;
+  let mut steps = 0;
+
+  while next_out < n {
+      steps += 1;
+      if steps > DP_MAX_STEPS {
+          return Err(ModelError::Diverged);
+      }
+      h = h.min(max_step).min(t_end - t_cur).max(min_step);
+
+      for s in 1..7 {
+          let mut y_stage = y_cur;
+          for j in 0..11 {
+              let mut acc = 0.0;
+              for (m, a) in DP_A[s].iter().enumerate().take(s) {
+                  acc += a * k[m][j];
+              }
+              y_stage[j] += h * acc;
+          }
+          k[s] = f(t_cur + DP_C[s] * h, &y_stage, params);
+      }
+
+      // The seventh stage is evaluated at the 5th-order solution (FSAL).
+      let mut y_new = y_cur;
+      let mut err = 0.0;
+      for j in 0..11 {
+          let mut acc = 0.0;
+          let mut err_j = 0.0;
+          for m in 0..6 {
+              acc += DP_A[6][m] * k[m][j];
+          }
+          for m in 0..7 {
+              err_j += DP_E[m] * k[m][j];
+          }
+          y_new[j] += h * acc;
+          let scale = atol + rtol * y_cur[j].abs().max(y_new[j].abs());
+          err += (h * err_j / scale).powi(2);
+      }
+      let err = (err / 11.0).sqrt();
+      if !err.is_finite() || y_new.iter().any(|value| !value.is_finite()) {
+          // Too large a step can overflow the stages; only give up at the smallest one.
+          if h <= min_step {
+              return Err(ModelError::Diverged);
+          }
+          h *= 0.2;
+          continue;
+      }
+
+      if err <= 1.0 || h <= min_step {
+          let t_new = t_cur + h;
+          while next_out < n && t[next_out] <= t_new + 1e-9 {
+              let s = ((t[next_out] - t_cur) / h).clamp(0.0, 1.0);
+              let s1 = 1.0 - s;
+              for j in 0..11 {
+                  let ydiff = y_new[j] - y_cur[j];
+                  let bspl = h * k[0][j] - ydiff;
+                  let mut dense = 0.0;
+                  for m in 0..7 {
+                      dense += DP_D[m] * k[m][j];
+                  }
+                  y[next_out][j] = y_cur[j]
+                      + s * (ydiff + s1 * (bspl + s * (ydiff - h * k[6][j] - bspl + s1 * h * dense)));
+              }
+              next_out += 1;
+          }
+          t_cur = t_new;
+          y_cur = y_new;
+          k[0] = k[6];
+      }
+
+      let factor = if err == 0.0 { 5.0 } else { (0.9 * err.powf(-0.2)).clamp(0.2, 5.0) };
+      h *= factor;
+  }
+
+  Ok(SimulationResult {
+      t: t.to_vec(),
       y,
+  })
+}
+
+/// Runs the configured solver for the circulation model over the grid `t`.
+pub fn integrate(
+  solver: &Solver,
+  y0: [f64; 11],
+  t: &[f64],
+  params: &CompiledParams,
+) -> Result<SimulationResult, ModelError> {
+  match *solver {
+      Solver::Rk4 => Ok(runge_kutta_4(pv_func, y0, t, params)),
+      Solver::Dopri5 { atol, rtol, max_step } => dormand_prince(pv_func, y0, t, params, atol, rtol, max_step),
+  }
+}
+
+/// Settings for the beat-by-beat run to a periodic steady state.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SteadyStateSettings {
+    /// Largest allowed beat-to-beat change in chamber volume (mL) or pressure (mmHg).
+    #[serde(default = "default_steady_state_tolerance")]
+    pub tolerance: f64,
+    #[serde(default = "default_max_beats")]
+    pub max_beats: usize,
+}
+
+fn default_steady_state_tolerance() -> f64 {
+    0.5
+}
+
+fn default_max_beats() -> usize {
+    30
+}
+
+impl Default for SteadyStateSettings {
+    fn default() -> Self {
+        Self {
+            tolerance: default_steady_state_tolerance(),
+            max_beats: default_max_beats(),
+        }
+    }
+}
+
+/// Everything that controls how a single parameter set is simulated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimulationSettings {
+    pub solver: Solver,
+    pub steady_state: SteadyStateSettings,
+}
+
+/// How the beat-by-beat run ended.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SteadyState {
+    pub converged: bool,
+    pub beats: usize,
+    /// Beat-to-beat change of the final beat, in mL / mmHg.
+    pub residual: f64,
+}
+
+const INITIAL_TIME: f64 = 954.931700000081;
+const OUTPUT_DT: f64 = 2.0;
+
+/// Heart rates (bpm) the model runs with. Slower beats would need an output grid
+/// too large to allocate; faster ones leave no room for the chamber activation.
+pub const HEART_RATE_RANGE: (f64, f64) = (20.0, 300.0);
+
+pub fn initial_state(params: &HemodynamicParams) -> Result<[f64; 11], ModelError> {
+  Ok([
+      params.value("Qvs_initial")?,
+      149.3527787113375, 405.08061599015554, 135.97317102061024,
+      144.32186565319813, 75.34345155268299, 117.70495107318685,
+      73.76400781737635, 68.42882775454605, 42.75963410693713,
+      20.28639894876003,
+  ])
+}
+
+/// Output grid for one beat starting at `t0`, always ending exactly at `t0 + period`.
+fn beat_grid(t0: f64, period: f64) -> Vec<f64> {
+  let samples = (period / OUTPUT_DT).floor() as usize;
+  let mut grid: Vec<f64> = (0..=samples).map(|j| t0 + j as f64 * OUTPUT_DT).collect();
+  if grid[samples] < t0 + period - 1e-9 {
+      grid.push(t0 + period);
+  }
+  grid
+}
+
+/// Chamber volumes and pressures at every sample of a beat, for beat-to-beat comparison.
+fn chamber_trace(sol: &SimulationResult, params: &CompiledParams, samples: usize) -> Vec<[f64; 8]> {
+  (0..samples)
+      .map(|i| {
+          let (pressures, _) = calculate_pressures_and_flows(sol.t[i], &sol.y[i], params);
+          let y = &sol.y[i];
+          [y[4], y[5], y[6], y[7], pressures[0], pressures[1], pressures[2], pressures[3]]
+      })
+      .collect()
+}
+
+/// Integrates one beat at a time from the default initial state until the chamber
+/// volumes and pressures repeat within `tolerance`, or `max_beats` is reached.
+/// Returns the final beat, or `Diverged` once any state or pressure is not finite.
+/// A heart rate outside `HEART_RATE_RANGE` is rejected before anything is allocated.
+pub fn simulate_steady_state(
+  params: &HemodynamicParams,
+  settings: &SimulationSettings,
+) -> Result<(SimulationResult, SteadyState), ModelError> {
+  let compiled = CompiledParams::try_from(params)?;
+  let (min_hr, max_hr) = HEART_RATE_RANGE;
+  if !(min_hr..=max_hr).contains(&compiled.HR) {
+      let mut errors = ValidationErrors::new();
+      errors.add("HR", format!("must be between {} and {} bpm", min_hr, max_hr));
+      return Err(ModelError::Invalid(errors));
+  }
+  let period = 60000.0 / compiled.HR;
+  let samples = (period / OUTPUT_DT).floor() as usize + 1;
+  let max_beats = settings.steady_state.max_beats.max(1);
+
+  let mut t0 = INITIAL_TIME;
+  let mut y0 = initial_state(params)?;
+  let mut previous: Option<Vec<[f64; 8]>> = None;
+  let mut residual = f64::INFINITY;
+  let mut beats = 0;
+
+  loop {
+      let grid = beat_grid(t0, period);
+      let sol = integrate(&settings.solver, y0, &grid, &compiled)?;
+      beats += 1;
+
+      let trace = chamber_trace(&sol, &compiled, samples);
+      let finite = |values: &[f64]| values.iter().all(|value| value.is_finite());
+      if !(sol.y.iter().all(|state| finite(state)) && trace.iter().all(|sample| finite(sample))) {
+          return Err(ModelError::Diverged);
+      }
+      if let Some(prev) = &previous {
+          residual = trace
+              .iter()
+              .zip(prev)
+              .flat_map(|(a, b)| a.iter().zip(b).map(|(x, y)| (x - y).abs()))
+              .fold(0.0, f64::max);
+      }
+
+      let converged = residual < settings.steady_state.tolerance;
+      if converged || beats >= max_beats {
+          return Ok((sol, SteadyState { converged, beats, residual }));
+      }
+
+      t0 += period;
+      y0 = sol.y[sol.y.len() - 1];
+      previous = Some(trace);
+  }
+}
+
+/// Time series of every state volume and the derived pressures and valve flows.
+#[derive(Debug, Serialize)]
+pub struct Waveforms {
+    /// Time (ms) from the start of the first steady-state beat.
+    pub t: Vec<f64>,
+    /// State volumes (mL), keyed by `STATE_NAMES`.
+    pub volumes: FxHashMap<String, Vec<f64>>,
+    /// Plv, Pla, Prv, Pra, Aop and PAP (mmHg).
+    pub pressures: FxHashMap<String, Vec<f64>>,
+    /// Imv, Iasp, Itv and Iapp (mL/ms).
+    pub flows: FxHashMap<String, Vec<f64>>,
+    pub steady_state: SteadyState,
+}
+
+/// Runs `params` to steady state, then records `beats` beats. Samples outside
+/// `window` (ms, relative to the first recorded beat) are dropped and only every
+/// `decimation`-th remaining sample is kept.
+pub fn simulate_waveforms(
+  params: &HemodynamicParams,
+  settings: &SimulationSettings,
+  beats: usize,
+  window: (Option<f64>, Option<f64>),
+  decimation: usize,
+) -> Result<Waveforms, ModelError> {
+  let compiled = CompiledParams::try_from(params)?;
+  let period = 60000.0 / compiled.HR;
+  let (first, steady_state) = simulate_steady_state(params, settings)?;
+
+  let t_start = first.t[0];
+  let mut t = first.t;
+  let mut y = first.y;
+  for _ in 1..beats.max(1) {
+      let t0 = t[t.len() - 1];
+      let sol = integrate(&settings.solver, y[y.len() - 1], &beat_grid(t0, period), &compiled)?;
+      t.extend_from_slice(&sol.t[1..]);
+      y.extend_from_slice(&sol.y[1..]);
+  }
+
+  let (from, to) = (window.0.unwrap_or(f64::MIN), window.1.unwrap_or(f64::MAX));
+  let mut waveforms = Waveforms {
+      t: Vec::new(),
+      volumes: FxHashMap::default(),
+      pressures: FxHashMap::default(),
+      flows: FxHashMap::default(),
+      steady_state,
+  };
+  let samples = t
+      .iter()
+      .zip(&y)
+      .filter(|(&time, _)| (from..=to).contains(&(time - t_start)))
+      .step_by(decimation.max(1));
+
+  for (&time, state) in samples {
+      let (pressures, _) = calculate_pressures_and_flows(time, state, &compiled);
+      let [Plv, Pla, Prv, Pra, Iasp, Iapp, Imv, Itv] = pressures;
+      let Aop = state[8] / compiled.Cas_prox + Iasp * compiled.Ras_prox;
+      let PAP = state[10] / compiled.Cap_prox + Iapp * compiled.Rap_prox;
+
+      waveforms.t.push(time - t_start);
+      for (name, volume) in STATE_NAMES.iter().zip(state) {
+          waveforms.volumes.entry(name.to_string()).or_default().push(*volume);
+      }
+      for (name, value) in [("Plv", Plv), ("Pla", Pla), ("Prv", Prv), ("Pra", Pra), ("Aop", Aop), ("PAP", PAP)] {
+          waveforms.pressures.entry(name.to_string()).or_default().push(value);
+      }
+      for (name, value) in [("Imv", Imv), ("Iasp", Iasp), ("Itv", Itv), ("Iapp", Iapp)] {
+          waveforms.flows.entry(name.to_string()).or_default().push(value);
+      }
+  }
+
+  Ok(waveforms)
+}
+
+
+/// Keys produced by `calculate_hemodynamic_metrics`, i.e. the valid `target_metrics` names.
+pub const METRIC_NAMES: &[&str] = &[
+    "stroke_volume",
+    "central_venous_pressure",
+    "pulmonary_capillary_wedge_pressure",
+    "systolic_arterial_pressure",
+    "diastolic_arterial_pressure",
+    "systolic_pulmonary_arterial_pressure",
+    "diastolic_pulmonary_arterial_pressure",
+    "left_ventricular_ejection_fraction",
+    "cardiac_output",
+    "mean_arterial_pressure",
+    "mean_pulmonary_arterial_pressure",
+    "systemic_vascular_resistance",
+    "pulmonary_vascular_resistance",
+    "transpulmonary_gradient",
+    "right_ventricular_stroke_volume",
+    "left_ventricular_end_diastolic_volume",
+    "left_ventricular_end_systolic_volume",
+    "right_ventricular_end_diastolic_volume",
+    "right_ventricular_end_systolic_volume",
+    "right_ventricular_ejection_fraction",
+    "left_atrial_maximum_volume",
+    "left_atrial_minimum_volume",
+    "right_atrial_maximum_volume",
+    "right_atrial_minimum_volume",
+    "left_atrial_emptying_fraction",
+    "left_ventricular_end_diastolic_pressure",
+    "right_ventricular_end_diastolic_pressure",
+    "left_ventricular_dpdt_max",
+    "left_ventricular_dpdt_min",
+    "right_ventricular_dpdt_max",
+    "right_ventricular_dpdt_min",
+    "left_ventricular_relaxation_time_constant",
+    "arterial_elastance",
+    "ventricular_arterial_coupling",
+    "pulmonary_arterial_elastance",
+    "left_ventricular_stroke_work",
+    "right_ventricular_stroke_work",
+    "left_ventricular_pressure_volume_area",
+    "right_ventricular_pressure_volume_area",
+    "myocardial_oxygen_consumption",
+    "left_ventricular_mechanical_efficiency",
+    "mitral_e_wave_peak_flow",
+    "mitral_a_wave_peak_flow",
+    "mitral_e_a_ratio",
+    "aortic_valve_peak_gradient",
+    "aortic_valve_mean_gradient",
+    "pulmonary_valve_peak_gradient",
+    "pulmonary_valve_mean_gradient",
+    "aortic_regurgitant_volume",
+    "aortic_regurgitant_fraction",
+    "mitral_regurgitant_volume",
+    "mitral_regurgitant_fraction",
+    "pulmonary_regurgitant_volume",
+    "pulmonary_regurgitant_fraction",
+    "tricuspid_regurgitant_volume",
+    "tricuspid_regurgitant_fraction",
+];
+
+/// Joules per mmHg·mL.
+const MMHG_ML_TO_J: f64 = 1.333e-4;
+/// Energy equivalent of oxygen (J per mL O2).
+const J_PER_ML_O2: f64 = 20.0;
+
+/// Largest rise and fall of `pressure` between consecutive samples, in mmHg/s.
+fn dpdt_extremes(pressure: &[f64], dt: f64) -> (f64, f64) {
+  pressure.windows(2).fold((f64::MIN, f64::MAX), |(max, min), pair| {
+      let dpdt = (pair[1] - pair[0]) / dt * 1000.0;
+      (max.max(dpdt), min.min(dpdt))
+  })
+}
+
+/// Isovolumic relaxation time constant (ms) by the Weiss method: a log-linear fit of
+/// pressure from peak negative dP/dt until the inflow valve opens. Reported as 0
+/// when the fit has fewer than two points or the pressure does not fall over them.
+fn relaxation_time_constant(t: &[f64], pressure: &[f64], inflow: &[f64]) -> f64 {
+  let start = (1..pressure.len())
+      .min_by(|&a, &b| (pressure[a] - pressure[a - 1]).total_cmp(&(pressure[b] - pressure[b - 1])))
+      .unwrap_or(0);
+  let points: Vec<(f64, f64)> = (start..pressure.len())
+      .take_while(|&i| inflow[i] <= 0.0 && pressure[i] > 0.0)
+      .map(|i| (t[i], pressure[i].ln()))
+      .collect();
+  if points.len() < 2 {
+      return 0.0;
   }
+
+  let n = points.len() as f64;
+  let mean_t = points.iter().map(|p| p.0).sum::<f64>() / n;
+  let mean_ln = points.iter().map(|p| p.1).sum::<f64>() / n;
+  let covariance: f64 = points.iter().map(|p| (p.0 - mean_t) * (p.1 - mean_ln)).sum();
+  let variance: f64 = points.iter().map(|p| (p.0 - mean_t).powi(2)).sum();
+  let tau = -variance / covariance;
+  if covariance < 0.0 && tau.is_finite() { tau } else { 0.0 }
 }
 
+/// Flow through one valve over a cycle and the pressure drop across it while open.
+/// Backflow through a competent valve is not counted as regurgitation.
+#[derive(Default)]
+struct ValveTally {
+  competent: bool,
+  forward: f64,
+  regurgitant: f64,
+  peak_gradient: f64,
+  gradient_sum: f64,
+  open_samples: usize,
+}
 
-pub fn calculate_hemodynamic_metrics(sol: &SimulationResult, params: &HemodynamicParams) -> FxHashMap<String, f64> {
+impl ValveTally {
+  fn new(regurgitant_resistance: f64) -> Self {
+      Self { competent: regurgitant_resistance == COMPETENT_VALVE_RESISTANCE, ..Self::default() }
+  }
+
+  fn add(&mut self, flow: f64, gradient: f64, dt: f64) {
+      if flow > 0.0 {
+          self.forward += flow * dt;
+          self.peak_gradient = self.peak_gradient.max(gradient);
+          self.gradient_sum += gradient;
+          self.open_samples += 1;
+      } else if !self.competent {
+          self.regurgitant -= flow * dt;
+      }
+  }
+
+  fn mean_gradient(&self) -> f64 {
+      if self.open_samples == 0 { 0.0 } else { self.gradient_sum / self.open_samples as f64 }
+  }
+
+  /// Regurgitant volume as a percentage of the forward volume.
+  fn regurgitant_fraction(&self) -> f64 {
+      if self.forward > 0.0 { self.regurgitant / self.forward * 100.0 } else { 0.0 }
+  }
+}
+
+/// Sample at peak activation of the chamber's elastance, i.e. end-systole.
+fn end_systole_index(t: &[f64], c: &Chamber, HR: f64) -> usize {
+  let activation = |time: f64| e(time - c.AV_delay, c.Tmax, c.tau, HR);
+  (0..t.len())
+      .max_by(|&a, &b| activation(t[a]).total_cmp(&activation(t[b])))
+      .unwrap_or(0)
+}
+
+/// Area enclosed by the pressure–volume loop (mmHg·mL), by the trapezoidal rule
+/// around the closed cycle.
+fn stroke_work(volume: &[f64], pressure: &[f64]) -> f64 {
+  let n = volume.len();
+  let work: f64 = (0..n)
+      .map(|i| {
+          let j = (i + 1) % n;
+          (pressure[i] + pressure[j]) / 2.0 * (volume[j] - volume[i])
+      })
+      .sum();
+  -work
+}
+
+/// Suga's pressure–volume area: stroke work plus the potential energy under the
+/// ESPVR, approximated by the triangle between `V0` and the end-systolic point.
+fn pressure_volume_area(stroke_work: f64, end_systolic_volume: f64, end_systolic_pressure: f64, c: &Chamber) -> f64 {
+  stroke_work + (end_systolic_volume - c.V0) * end_systolic_pressure / 2.0
+}
+
+/// Metrics of the last cardiac cycle of `sol`, keyed by `METRIC_NAMES`. Fails with
+/// `Diverged` if any state or pressure in that cycle is not finite.
+pub fn calculate_hemodynamic_metrics(
+  sol: &SimulationResult,
+  params: &HemodynamicParams,
+) -> Result<FxHashMap<String, f64>, ModelError> {
   let t = &sol.t;
   let states = &sol.y;
+  if t.len() < 2 {
+      return Err(ModelError::NoCycle);
+  }
   let dt = t[1] - t[0];
 
-  let cycle_duration = 60000.0 / params.parameters["HR"].value;
+  let compiled = CompiledParams::try_from(params)?;
+  let cycle_duration = 60000.0 / compiled.HR;
   let end_time = t[t.len() - 1];
   let start_time = end_time - cycle_duration;
 
-  let mut cycle_indices = [0; 5001];
-  let mut cycle_index_count = 0;
+  let mut cycle_indices = Vec::with_capacity(t.len());
 
   for (i, &time) in t.iter().enumerate() {
       if time >= start_time && time <= end_time {
-          cycle_indices[cycle_index_count] = i;
-          cycle_index_count += 1;
+          cycle_indices.push(i);
       }
   }
+  let cycle_index_count = cycle_indices.len();
 
   if cycle_index_count == 0 {
-      panic!("No cycle indices found. Check the cycle duration and timing.");
+      return Err(ModelError::NoCycle);
   }
 
   let mut stroke_volume = 0.0;
@@ -177,20 +762,56 @@ pub fn calculate_hemodynamic_metrics(sol: &SimulationResult, params: &Hemodynami
   let mut diastolic_arterial_pressure = f64::MAX;
   let mut systolic_pulmonary_arterial_pressure = f64::MIN;
   let mut diastolic_pulmonary_arterial_pressure = f64::MAX;
+  let mut mean_arterial_pressure = 0.0;
+  let mut mean_pulmonary_arterial_pressure = 0.0;
+  let mut right_ventricular_stroke_volume = 0.0;
 
   let mut LVEDV = f64::MIN;
   let mut LVESV = f64::MAX;
+  let mut RVEDV = f64::MIN;
+  let mut RVESV = f64::MAX;
+  let mut LAVmax = f64::MIN;
+  let mut LAVmin = f64::MAX;
+  let mut RAVmax = f64::MIN;
+  let mut RAVmin = f64::MAX;
+  // End-diastolic pressures are sampled at the onset of ventricular activation,
+  // the same point `/pv-loops` reports as end_diastole.
+  let (mut LVEDP, mut LV_onset) = (0.0, f64::MAX);
+  let (mut RVEDP, mut RV_onset) = (0.0, f64::MAX);
+  let mut cycle_t = Vec::with_capacity(cycle_index_count);
+  let mut Plv_cycle = Vec::with_capacity(cycle_index_count);
+  let mut Prv_cycle = Vec::with_capacity(cycle_index_count);
+  let mut Imv_cycle = Vec::with_capacity(cycle_index_count);
+  let mut Qlv_cycle = Vec::with_capacity(cycle_index_count);
+  let mut Qrv_cycle = Vec::with_capacity(cycle_index_count);
+  let mut aortic_valve = ValveTally::new(compiled.Ravr);
+  let mut mitral_valve = ValveTally::new(compiled.Rmvr);
+  let mut pulmonary_valve = ValveTally::new(compiled.Rpvr);
+  let mut tricuspid_valve = ValveTally::new(compiled.Rtvr);
+  // Mitral inflow during atrial systole (from LA to LV activation onset) forms the
+  // A wave; the rest of diastolic inflow is the E wave. Peaks in mL/s.
+  let mut mitral_e_wave_peak_flow = 0.0_f64;
+  let mut mitral_a_wave_peak_flow = 0.0_f64;
+  let atrioventricular_interval = (compiled.LV.AV_delay - compiled.LA.AV_delay).rem_euclid(cycle_duration);
 
-  let cas_prox = params.parameters["Cas_prox"].value;
-  let ras_prox = params.parameters["Ras_prox"].value;
-  let cap_prox = params.parameters["Cap_prox"].value;
-  let rap_prox = params.parameters["Rap_prox"].value;
+  let cas_prox = compiled.Cas_prox;
+  let ras_prox = compiled.Ras_prox;
+  let cap_prox = compiled.Cap_prox;
+  let rap_prox = compiled.Rap_prox;
   
-  for &i in cycle_indices.iter().take(cycle_index_count) {
-      let (pressures, _) = calculate_pressures_and_flows(t[i], &states[i], params);
-      let [Plv, Pla, Prv, Pra, Iasp, Iapp] = [pressures[0], pressures[1], pressures[2], pressures[3], pressures[4], pressures[5]];
+  for &i in &cycle_indices {
+      let (pressures, _) = calculate_pressures_and_flows(t[i], &states[i], &compiled);
+      if !states[i].iter().chain(&pressures).all(|value| value.is_finite()) {
+          return Err(ModelError::Diverged);
+      }
+      let [Plv, Pla, Prv, Pra, Iasp, Iapp, Imv, Itv] = pressures;
+      cycle_t.push(t[i]);
+      Plv_cycle.push(Plv);
+      Prv_cycle.push(Prv);
+      Imv_cycle.push(Imv);
   
       stroke_volume += Iasp.max(0.0) * dt;
+      right_ventricular_stroke_volume += Iapp.max(0.0) * dt;
   
       central_venous_pressure += Pra;
       pulmonary_capillary_wedge_pressure += Pla;
@@ -198,21 +819,83 @@ pub fn calculate_hemodynamic_metrics(sol: &SimulationResult, params: &Hemodynami
       let Aop = states[i][8] / cas_prox + Iasp * ras_prox;
       systolic_arterial_pressure = systolic_arterial_pressure.max(Aop);
       diastolic_arterial_pressure = diastolic_arterial_pressure.min(Aop);
+      mean_arterial_pressure += Aop;
   
       let PAP = states[i][10] / cap_prox + Iapp * rap_prox;
       systolic_pulmonary_arterial_pressure = systolic_pulmonary_arterial_pressure.max(PAP);
       diastolic_pulmonary_arterial_pressure = diastolic_pulmonary_arterial_pressure.min(PAP);
+      mean_pulmonary_arterial_pressure += PAP;
+
+      aortic_valve.add(Iasp, Plv - Aop, dt);
+      mitral_valve.add(Imv, Pla - Plv, dt);
+      pulmonary_valve.add(Iapp, Prv - PAP, dt);
+      tricuspid_valve.add(Itv, Pra - Prv, dt);
+      if (t[i] - compiled.LA.AV_delay).rem_euclid(cycle_duration) < atrioventricular_interval {
+          mitral_a_wave_peak_flow = mitral_a_wave_peak_flow.max(Imv * 1000.0);
+      } else {
+          mitral_e_wave_peak_flow = mitral_e_wave_peak_flow.max(Imv * 1000.0);
+      }
   
-      let Qlv = states[i][4];
+      let [Qlv, Qla, Qrv, Qra] = [states[i][4], states[i][5], states[i][6], states[i][7]];
+      Qlv_cycle.push(Qlv);
+      Qrv_cycle.push(Qrv);
       LVEDV = LVEDV.max(Qlv);
       LVESV = LVESV.min(Qlv);
+      RVEDV = RVEDV.max(Qrv);
+      RVESV = RVESV.min(Qrv);
+      let distance = activation_onset_distance(t[i], &compiled.LV, compiled.HR);
+      if distance < LV_onset {
+          (LVEDP, LV_onset) = (Plv, distance);
+      }
+      let distance = activation_onset_distance(t[i], &compiled.RV, compiled.HR);
+      if distance < RV_onset {
+          (RVEDP, RV_onset) = (Prv, distance);
+      }
+      LAVmax = LAVmax.max(Qla);
+      LAVmin = LAVmin.min(Qla);
+      RAVmax = RAVmax.max(Qra);
+      RAVmin = RAVmin.min(Qra);
   }
 
   let cycle_len = cycle_index_count as f64;
   central_venous_pressure /= cycle_len;
   pulmonary_capillary_wedge_pressure /= cycle_len;
+  mean_arterial_pressure /= cycle_len;
+  mean_pulmonary_arterial_pressure /= cycle_len;
 
   let left_ventricular_ejection_fraction = (LVEDV - LVESV) / LVEDV * 100.0;
+  let right_ventricular_ejection_fraction = (RVEDV - RVESV) / RVEDV * 100.0;
+  let left_atrial_emptying_fraction = (LAVmax - LAVmin) / LAVmax * 100.0;
+
+  // L/min; resistances in Wood units (mmHg·min/L), multiply by 80 for dyn·s/cm⁵.
+  let cardiac_output = stroke_volume * compiled.HR / 1000.0;
+  let transpulmonary_gradient = mean_pulmonary_arterial_pressure - pulmonary_capillary_wedge_pressure;
+  let systemic_vascular_resistance = (mean_arterial_pressure - central_venous_pressure) / cardiac_output;
+  let pulmonary_vascular_resistance = transpulmonary_gradient / cardiac_output;
+
+  let (left_ventricular_dpdt_max, left_ventricular_dpdt_min) = dpdt_extremes(&Plv_cycle, dt);
+  let (right_ventricular_dpdt_max, right_ventricular_dpdt_min) = dpdt_extremes(&Prv_cycle, dt);
+  let left_ventricular_relaxation_time_constant = relaxation_time_constant(&cycle_t, &Plv_cycle, &Imv_cycle);
+  let lv_es = end_systole_index(&cycle_t, &compiled.LV, compiled.HR);
+  let rv_es = end_systole_index(&cycle_t, &compiled.RV, compiled.HR);
+  // Effective arterial elastances, Pes / SV (mmHg/mL).
+  let arterial_elastance = Plv_cycle[lv_es] / stroke_volume;
+  let pulmonary_arterial_elastance = Prv_cycle[rv_es] / right_ventricular_stroke_volume;
+  let ventricular_arterial_coupling = compiled.LV.Ees / arterial_elastance;
+
+  let left_ventricular_stroke_work = stroke_work(&Qlv_cycle, &Plv_cycle);
+  let right_ventricular_stroke_work = stroke_work(&Qrv_cycle, &Prv_cycle);
+  let left_ventricular_pressure_volume_area =
+      pressure_volume_area(left_ventricular_stroke_work, Qlv_cycle[lv_es], Plv_cycle[lv_es], &compiled.LV);
+  let right_ventricular_pressure_volume_area =
+      pressure_volume_area(right_ventricular_stroke_work, Qrv_cycle[rv_es], Prv_cycle[rv_es], &compiled.RV);
+  // Suga's MVO2 = a·PVA + b·Ees + c per beat (canine coefficients), reported in mL O2/min.
+  let oxygen_per_beat = 1.8e-5 * left_ventricular_pressure_volume_area + 2.4e-3 * compiled.LV.Ees + 2.8e-3;
+  let myocardial_oxygen_consumption = oxygen_per_beat * compiled.HR;
+  let left_ventricular_mechanical_efficiency =
+      left_ventricular_stroke_work * MMHG_ML_TO_J / (oxygen_per_beat * J_PER_ML_O2) * 100.0;
+  let mitral_e_a_ratio =
+      if mitral_a_wave_peak_flow > 0.0 { mitral_e_wave_peak_flow / mitral_a_wave_peak_flow } else { 0.0 };
 
   let mut metrics = FxHashMap::default();
   metrics.insert("stroke_volume".to_string(), stroke_volume);
@@ -223,44 +906,178 @@ pub fn calculate_hemodynamic_metrics(sol: &SimulationResult, params: &Hemodynami
   metrics.insert("systolic_pulmonary_arterial_pressure".to_string(), systolic_pulmonary_arterial_pressure);
   metrics.insert("diastolic_pulmonary_arterial_pressure".to_string(), diastolic_pulmonary_arterial_pressure);
   metrics.insert("left_ventricular_ejection_fraction".to_string(), left_ventricular_ejection_fraction);
+  metrics.insert("cardiac_output".to_string(), cardiac_output);
+  metrics.insert("mean_arterial_pressure".to_string(), mean_arterial_pressure);
+  metrics.insert("mean_pulmonary_arterial_pressure".to_string(), mean_pulmonary_arterial_pressure);
+  metrics.insert("systemic_vascular_resistance".to_string(), systemic_vascular_resistance);
+  metrics.insert("pulmonary_vascular_resistance".to_string(), pulmonary_vascular_resistance);
+  metrics.insert("transpulmonary_gradient".to_string(), transpulmonary_gradient);
+  metrics.insert("right_ventricular_stroke_volume".to_string(), right_ventricular_stroke_volume);
+  metrics.insert("left_ventricular_end_diastolic_volume".to_string(), LVEDV);
+  metrics.insert("left_ventricular_end_systolic_volume".to_string(), LVESV);
+  metrics.insert("right_ventricular_end_diastolic_volume".to_string(), RVEDV);
+  metrics.insert("right_ventricular_end_systolic_volume".to_string(), RVESV);
+  metrics.insert("right_ventricular_ejection_fraction".to_string(), right_ventricular_ejection_fraction);
+  metrics.insert("left_atrial_maximum_volume".to_string(), LAVmax);
+  metrics.insert("left_atrial_minimum_volume".to_string(), LAVmin);
+  metrics.insert("right_atrial_maximum_volume".to_string(), RAVmax);
+  metrics.insert("right_atrial_minimum_volume".to_string(), RAVmin);
+  metrics.insert("left_atrial_emptying_fraction".to_string(), left_atrial_emptying_fraction);
+  metrics.insert("left_ventricular_end_diastolic_pressure".to_string(), LVEDP);
+  metrics.insert("right_ventricular_end_diastolic_pressure".to_string(), RVEDP);
+  metrics.insert("left_ventricular_dpdt_max".to_string(), left_ventricular_dpdt_max);
+  metrics.insert("left_ventricular_dpdt_min".to_string(), left_ventricular_dpdt_min);
+  metrics.insert("right_ventricular_dpdt_max".to_string(), right_ventricular_dpdt_max);
+  metrics.insert("right_ventricular_dpdt_min".to_string(), right_ventricular_dpdt_min);
+  metrics.insert("left_ventricular_relaxation_time_constant".to_string(), left_ventricular_relaxation_time_constant);
+  metrics.insert("arterial_elastance".to_string(), arterial_elastance);
+  metrics.insert("ventricular_arterial_coupling".to_string(), ventricular_arterial_coupling);
+  metrics.insert("pulmonary_arterial_elastance".to_string(), pulmonary_arterial_elastance);
+  metrics.insert("left_ventricular_stroke_work".to_string(), left_ventricular_stroke_work);
+  metrics.insert("right_ventricular_stroke_work".to_string(), right_ventricular_stroke_work);
+  metrics.insert("left_ventricular_pressure_volume_area".to_string(), left_ventricular_pressure_volume_area);
+  metrics.insert("right_ventricular_pressure_volume_area".to_string(), right_ventricular_pressure_volume_area);
+  metrics.insert("myocardial_oxygen_consumption".to_string(), myocardial_oxygen_consumption);
+  metrics.insert("left_ventricular_mechanical_efficiency".to_string(), left_ventricular_mechanical_efficiency);
+  metrics.insert("mitral_e_wave_peak_flow".to_string(), mitral_e_wave_peak_flow);
+  metrics.insert("mitral_a_wave_peak_flow".to_string(), mitral_a_wave_peak_flow);
+  metrics.insert("mitral_e_a_ratio".to_string(), mitral_e_a_ratio);
+  metrics.insert("aortic_valve_peak_gradient".to_string(), aortic_valve.peak_gradient);
+  metrics.insert("aortic_valve_mean_gradient".to_string(), aortic_valve.mean_gradient());
+  metrics.insert("pulmonary_valve_peak_gradient".to_string(), pulmonary_valve.peak_gradient);
+  metrics.insert("pulmonary_valve_mean_gradient".to_string(), pulmonary_valve.mean_gradient());
+  for (valve, tally) in [
+      ("aortic", &aortic_valve),
+      ("mitral", &mitral_valve),
+      ("pulmonary", &pulmonary_valve),
+      ("tricuspid", &tricuspid_valve),
+  ] {
+      metrics.insert(format!("{}_regurgitant_volume", valve), tally.regurgitant);
+      metrics.insert(format!("{}_regurgitant_fraction", valve), tally.regurgitant_fraction());
+  }
 
-  metrics
+  Ok(metrics)
 }
 
 
-pub fn evaluate(params: &HemodynamicParams, target_metrics: &[(f64, String, f64)]) -> f64 {
-  let default_data = [
-      params.parameters["Qvs_initial"].value,
-      149.3527787113375, 405.08061599015554, 135.97317102061024,
-      144.32186565319813, 75.34345155268299, 117.70495107318685,
-      73.76400781737635, 68.42882775454605, 42.75963410693713,
-      20.28639894876003,
-  ];
-  let default_time = 954.931700000081;
-  let dt = 2.0;
-  let mut t_eval = [0.0; 5001];
-  for i in 0..t_eval.len() {
-      t_eval[i] = default_time + i as f64 * dt;
+/// One term of the objective: `weight * max(|value - target| - tolerance, 0)^2`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetMetric {
+  pub metric: String,
+  pub target: f64,
+  #[serde(default = "default_weight")]
+  pub weight: f64,
+  /// Half-width of a dead band around `target` inside which the metric costs nothing.
+  #[serde(default)]
+  pub tolerance: f64,
+}
+
+fn default_weight() -> f64 {
+  1.0
+}
+
+/// Positional `(target, metric, weight)` form used by the v1 API.
+impl From<(f64, String, f64)> for TargetMetric {
+  fn from((target, metric, weight): (f64, String, f64)) -> Self {
+      Self { metric, target, weight, tolerance: 0.0 }
+  }
+}
+
+/// Targets measured under one condition (rest, dobutamine, fluid challenge, ...).
+/// `overrides` turn the shared parameter set into this condition's; everything
+/// else is shared between conditions.
+#[derive(Debug, Clone, Default)]
+pub struct Condition {
+  pub name: String,
+  pub overrides: FxHashMap<String, ParamOverride>,
+  pub metrics: Vec<TargetMetric>,
+  pub waveforms: Vec<WaveformTarget>,
+}
+
+impl Condition {
+  /// The shared parameter set as seen under this condition.
+  pub fn params(&self, shared: &HemodynamicParams) -> Result<HemodynamicParams, ModelError> {
+      let mut params = shared.clone();
+      params.apply_overrides(&self.overrides)?;
+      Ok(params)
   }
 
-  let sol = runge_kutta_4(pv_func, default_data, &t_eval, &params);
+  fn error(&self, params: &HemodynamicParams, settings: &SimulationSettings) -> Result<f64, ModelError> {
+      let (sol, _) = simulate_steady_state(params, settings)?;
 
-  let metrics = calculate_hemodynamic_metrics(&sol, params);
-  let mut error = 0.0;
-  for (target, key, weight) in target_metrics {
-      if let Some(&value) = metrics.get(key.as_str()) {
-          error += weight * (target - value).powi(2);
+      let mut error = 0.0;
+      if !self.metrics.is_empty() {
+          let metrics = calculate_hemodynamic_metrics(&sol, params)?;
+          for target in &self.metrics {
+              let value = metrics
+                  .get(target.metric.as_str())
+                  .ok_or_else(|| ModelError::UnknownMetric(target.metric.clone()))?;
+              let excess = ((value - target.target).abs() - target.tolerance).max(0.0);
+              error += target.weight * excess.powi(2);
+          }
       }
+      if !self.waveforms.is_empty() {
+          let compiled = CompiledParams::try_from(params)?;
+          for target in &self.waveforms {
+              error += target.weight * waveform::fit_waveform(&sol, target, &compiled)?.mean_squared_error;
+          }
+      }
+      Ok(error)
   }
-  error
 }
 
+/// Everything the fitness of a parameter set is measured against: the summed
+/// error of all conditions, which share one parameter set.
+#[derive(Debug, Clone, Default)]
+pub struct Objective {
+  pub conditions: Vec<Condition>,
+}
+
+impl Objective {
+  /// A single condition using the parameters as they are.
+  pub fn new(metrics: Vec<TargetMetric>, waveforms: Vec<WaveformTarget>) -> Self {
+      Self { conditions: vec![Condition { metrics, waveforms, ..Condition::default() }] }
+  }
+}
+
+/// Fitness of `params` against `objective`; lower is better. A parameter set whose
+/// simulation diverges scores infinity, so the optimizers simply move away from it.
+pub fn evaluate(params: &HemodynamicParams, objective: &Objective, settings: &SimulationSettings) -> Result<f64, ModelError> {
+  let mut fitness = 0.0;
+  for condition in &objective.conditions {
+      let error = if condition.overrides.is_empty() {
+          condition.error(params, settings)
+      } else {
+          condition.error(&condition.params(params)?, settings)
+      };
+      fitness += match error {
+          Err(ModelError::Diverged) => return Ok(f64::INFINITY),
+          error => error?,
+      };
+  }
+  Ok(if fitness.is_nan() { f64::INFINITY } else { fitness })
+}
+
+
+
+fn splitmix64(mut x: u64) -> u64 {
+  x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+  x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  x ^ (x >> 31)
+}
 
+/// RNG for one independent stream of a seeded run, e.g. `[repeat, generation, index]`.
+/// Deriving streams from their position keeps results independent of thread scheduling.
+pub(crate) fn stream_rng(seed: u64, path: &[u64]) -> Pcg32 {
+  let state = path.iter().fold(splitmix64(seed), |state, &part| splitmix64(state ^ splitmix64(part)));
+  Pcg32::seed_from_u64(state)
+}
 
-pub fn generate_individual() -> HemodynamicParams {
-  let mut rng = Pcg32::seed_from_u64(rand::thread_rng().gen());
-  let mut params = HemodynamicParams::new();
-  for (name, param) in params.parameters.iter_mut() {
+pub(crate) fn generate_individual(base: &HemodynamicParams, rng: &mut Pcg32) -> HemodynamicParams {
+  let mut params = base.clone();
+  for param in params.parameters.values_mut() {
       if param.fitting {
           param.value = rng.gen_range(param.range.0..param.range.1);
       }
@@ -271,28 +1088,30 @@ pub fn generate_individual() -> HemodynamicParams {
 
 
 
-fn crossover(parent1: &HemodynamicParams, parent2: &HemodynamicParams) -> HemodynamicParams {
-  let mut rng = Pcg32::seed_from_u64(rand::thread_rng().gen());
+fn crossover(parent1: &HemodynamicParams, parent2: &HemodynamicParams, probability: f64, rng: &mut Pcg32) -> HemodynamicParams {
   let mut child = parent1.clone();
+  if !rng.gen_bool(probability) {
+      return child;
+  }
   for (name, param) in child.parameters.iter_mut() {
       if param.fitting && rng.gen_bool(0.5) {
-          param.value = parent2.parameters[name].value;
+          if let Some(other) = parent2.parameters.get(name) {
+              param.value = other.value;
+          }
       }
   }
   child
 }
 
-fn mutate(individual: &mut HemodynamicParams, mutation_rate: f64) {
-  let mut rng = Pcg32::seed_from_u64(rand::thread_rng().gen());
+fn mutate(individual: &mut HemodynamicParams, mutation_rate: f64, rng: &mut Pcg32) {
   for param in individual.parameters.values_mut() {
-      if param.fitting && rng.gen_bool(mutation_rate as f64) {
+      if param.fitting && rng.gen_bool(mutation_rate) {
           param.value = rng.gen_range(param.range.0..param.range.1);
       }
   }
 }
 
-fn tournament_selection(population: &Vec<(HemodynamicParams, f64)>, tournament_size: usize) -> HemodynamicParams {
-  let mut rng = Pcg32::seed_from_u64(rand::thread_rng().gen());
+fn tournament_selection(population: &[(HemodynamicParams, f64)], tournament_size: usize, rng: &mut Pcg32) -> HemodynamicParams {
   let mut best = &population[rng.gen_range(0..population.len())];
   for _ in 0..tournament_size-1 {
       let individual = &population[rng.gen_range(0..population.len())];
@@ -302,90 +1121,506 @@ fn tournament_selection(population: &Vec<(HemodynamicParams, f64)>, tournament_s
   }
   best.0.clone()
 }
-fn run_single(
-  target_metrics: &[(f64, String, f64)],
-  param_updates: Option<FxHashMap<String, (Option<f64>, Option<(f64, f64)>, Option<bool>)>>,
-  threads_per_start: usize,
-) -> (HemodynamicParams, f64) {
-  let mut params = HemodynamicParams::default();
 
-  if let Some(updates) = param_updates {
-      for (name, (value, range, fitting)) in updates {
-          params.update(&name, value, range, fitting);
-      }
-  }
+/// Snapshot of an optimization after one generation.
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub repeat: usize,
+    pub generation: usize,
+    pub generations: usize,
+    pub best_fitness: f64,
+    pub median_fitness: f64,
+    /// Mutation rate for the genetic algorithm, step size σ for CMA-ES.
+    pub mutation_rate: f64,
+    pub best_parameters: HemodynamicParams,
+}
+
+/// Rules for ending an optimization before its last generation. All are checked
+/// after every generation; unset rules never trigger.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoppingCriteria {
+    /// Stop once the best fitness is at or below this value.
+    pub target_fitness: Option<f64>,
+    /// Stop after this many generations without any improvement of the best fitness.
+    pub patience: Option<usize>,
+    /// Stop when the best fitness improved by less than this fraction over the
+    /// last `improvement_window` generations.
+    pub min_relative_improvement: Option<f64>,
+    pub improvement_window: usize,
+    /// Wall-clock budget per repeat, in seconds.
+    pub max_seconds: Option<f64>,
+    /// Budget of `evaluate` calls per repeat.
+    pub max_evaluations: Option<usize>,
+}
+
+impl Default for StoppingCriteria {
+    fn default() -> Self {
+        Self {
+            target_fitness: None,
+            patience: None,
+            min_relative_improvement: None,
+            improvement_window: 10,
+            max_seconds: None,
+            max_evaluations: None,
+        }
+    }
+}
+
+/// Why an optimization run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    MaxGenerations,
+    TargetReached,
+    NoImprovement,
+    SmallImprovement,
+    TimeBudget,
+    MaxEvaluations,
+    Cancelled,
+}
+
+/// How a single optimization run ended.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RunOutcome {
+    pub stop_reason: StopReason,
+    pub generations: usize,
+    pub evaluations: usize,
+}
+
+/// Applies `StoppingCriteria` to the best fitness of successive generations.
+pub(crate) struct StopTracker {
+    criteria: StoppingCriteria,
+    started: Instant,
+    history: Vec<f64>,
+}
 
-  let population_size = 100;
-  let generations = 200;
-  let elite_count = 1;
-  let tournament_size = 3;
-  let initial_mutation_rate = 0.1;
-  let final_mutation_rate = 0.01;
+impl StopTracker {
+    pub(crate) fn check(&mut self, best_fitness: f64, evaluations: usize) -> Option<StopReason> {
+        let criteria = &self.criteria;
+        self.history.push(best_fitness);
+        let len = self.history.len();
 
-  let pool = ThreadPoolBuilder::new().num_threads(threads_per_start).build().unwrap();
+        if criteria.target_fitness.is_some_and(|target| best_fitness <= target) {
+            return Some(StopReason::TargetReached);
+        }
+        if let Some(patience) = criteria.patience {
+            if len > patience && self.history[len - 1 - patience] <= best_fitness {
+                return Some(StopReason::NoImprovement);
+            }
+        }
+        if let Some(epsilon) = criteria.min_relative_improvement {
+            let window = criteria.improvement_window.max(1);
+            if len > window {
+                let earlier = self.history[len - 1 - window];
+                if (earlier - best_fitness) / earlier.abs().max(f64::MIN_POSITIVE) < epsilon {
+                    return Some(StopReason::SmallImprovement);
+                }
+            }
+        }
+        if criteria.max_evaluations.is_some_and(|max| evaluations >= max) {
+            return Some(StopReason::MaxEvaluations);
+        }
+        if criteria.max_seconds.is_some_and(|max| self.started.elapsed().as_secs_f64() >= max) {
+            return Some(StopReason::TimeBudget);
+        }
+        None
+    }
+}
+
+type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Lets a caller steer and observe an optimization that runs on another thread.
+#[derive(Clone, Default)]
+pub struct RunControl {
+    cancel: Arc<AtomicBool>,
+    on_progress: Option<ProgressCallback>,
+    stopping: StoppingCriteria,
+}
+
+impl RunControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `callback` after every generation, from the optimizer's thread.
+    pub fn with_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Ends runs early according to `criteria`.
+    pub fn with_stopping(mut self, criteria: StoppingCriteria) -> Self {
+        self.stopping = criteria;
+        self
+    }
+
+    /// Starts tracking a new run against the stopping criteria.
+    pub(crate) fn stop_tracker(&self) -> StopTracker {
+        StopTracker {
+            criteria: self.stopping,
+            started: Instant::now(),
+            history: Vec::new(),
+        }
+    }
+
+    pub(crate) fn report(&self, progress: impl FnOnce() -> Progress) {
+        if let Some(callback) = &self.on_progress {
+            callback(&progress());
+        }
+    }
+
+    /// Asks the optimizer to stop after the current generation.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+/// How the mutation rate moves from `initial_rate` to `final_rate` over the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MutationShape {
+    #[default]
+    Linear,
+    Exponential,
+    Constant,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MutationSchedule {
+    pub initial_rate: f64,
+    pub final_rate: f64,
+    pub shape: MutationShape,
+}
+
+impl Default for MutationSchedule {
+    fn default() -> Self {
+        Self {
+            initial_rate: 0.1,
+            final_rate: 0.01,
+            shape: MutationShape::Linear,
+        }
+    }
+}
+
+impl MutationSchedule {
+    /// Mutation rate at `generation` out of `generations`.
+    pub fn rate(&self, generation: usize, generations: usize) -> f64 {
+        let progress = generation as f64 / generations as f64;
+        match self.shape {
+            MutationShape::Linear => self.initial_rate - (self.initial_rate - self.final_rate) * progress,
+            MutationShape::Exponential => self.initial_rate * (self.final_rate / self.initial_rate).powf(progress),
+            MutationShape::Constant => self.initial_rate,
+        }
+    }
+}
+
+/// Hyperparameters of the genetic algorithm in `run_single`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GaSettings {
+    pub population_size: usize,
+    pub generations: usize,
+    pub elite_count: usize,
+    pub tournament_size: usize,
+    /// Chance that a child mixes both parents; otherwise it copies the first.
+    pub crossover_probability: f64,
+    pub mutation: MutationSchedule,
+}
+
+impl Default for GaSettings {
+    fn default() -> Self {
+        Self {
+            population_size: 100,
+            generations: 200,
+            elite_count: 1,
+            tournament_size: 3,
+            crossover_probability: 1.0,
+            mutation: MutationSchedule::default(),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_single(
+  repeat: usize,
+  seed: u64,
+  objective: &Objective,
+  base: &HemodynamicParams,
+  ga: &GaSettings,
+  threads_per_start: usize,
+  settings: &SimulationSettings,
+  control: &RunControl,
+) -> Result<(HemodynamicParams, f64, RunOutcome), ModelError> {
+  let GaSettings { population_size, generations, elite_count, tournament_size, crossover_probability, mutation } = *ga;
+
+  let pool = ThreadPoolBuilder::new().num_threads(threads_per_start).build()?;
 
   let mut population: Vec<(HemodynamicParams, f64)> = pool.install(|| {
       (0..population_size)
           .into_par_iter()
-          .map(|_| {
-              let individual = generate_individual();
-              let fitness = evaluate(&individual, target_metrics);
-              (individual, fitness)
+          .map(|index| {
+              let mut rng = stream_rng(seed, &[repeat as u64, 0, index as u64]);
+              let individual = generate_individual(base, &mut rng);
+              let fitness = evaluate(&individual, objective, settings)?;
+              Ok((individual, fitness))
           })
-          .collect()
-  });
+          .collect::<Result<_, ModelError>>()
+  })?;
 
-  for generation in 0..generations {
-      println!("Generation: {}/{}", generation + 1, generations);
+  let mut tracker = control.stop_tracker();
+  let mut outcome = RunOutcome {
+      stop_reason: StopReason::MaxGenerations,
+      generations: 0,
+      evaluations: population_size,
+  };
 
-      population.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+  for generation in 0..generations {
+      if control.is_cancelled() {
+          outcome.stop_reason = StopReason::Cancelled;
+          break;
+      }
+      population.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
       let mut new_population = population.iter().take(elite_count).cloned().collect::<Vec<_>>();
 
-      let mutation_rate = initial_mutation_rate - (initial_mutation_rate - final_mutation_rate) * (generation as f64 / generations as f64);
+      let mutation_rate = mutation.rate(generation, generations);
 
       let offspring = pool.install(|| {
           (0..population_size - elite_count)
               .into_par_iter()
-              .map(|_| {
-                  let parent1 = tournament_selection(&population, tournament_size);
-                  let parent2 = tournament_selection(&population, tournament_size);
-                  let mut child = crossover(&parent1, &parent2);
-                  mutate(&mut child, mutation_rate);
-                  let fitness = evaluate(&child, target_metrics);
-                  (child, fitness)
+              .map(|index| {
+                  let mut rng = stream_rng(seed, &[repeat as u64, generation as u64 + 1, index as u64]);
+                  let parent1 = tournament_selection(&population, tournament_size, &mut rng);
+                  let parent2 = tournament_selection(&population, tournament_size, &mut rng);
+                  let mut child = crossover(&parent1, &parent2, crossover_probability, &mut rng);
+                  mutate(&mut child, mutation_rate, &mut rng);
+                  let fitness = evaluate(&child, objective, settings)?;
+                  Ok((child, fitness))
               })
-              .collect::<Vec<_>>()
-      });
+              .collect::<Result<Vec<_>, ModelError>>()
+      })?;
 
       new_population.extend(offspring);
       population = new_population;
+      outcome.generations = generation + 1;
+      outcome.evaluations += population_size - elite_count;
 
       let mut best_fitness = population.iter().map(|(_, fitness)| *fitness).collect::<Vec<_>>();
-      best_fitness.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-      let top_three_fitness = best_fitness.iter().take(3).cloned().collect::<Vec<_>>();
-      println!("Fitness at generation {}: {:?}", generation, top_three_fitness);
+      best_fitness.sort_unstable_by(|a, b| a.total_cmp(b));
+
+      control.report(|| Progress {
+          repeat,
+          generation: generation + 1,
+          generations,
+          best_fitness: best_fitness[0],
+          median_fitness: best_fitness[best_fitness.len() / 2],
+          mutation_rate,
+          best_parameters: population
+              .iter()
+              .min_by(|a, b| a.1.total_cmp(&b.1))
+              .map(|(individual, _)| individual.clone())
+              .unwrap_or_default(),
+      });
+
+      if let Some(reason) = tracker.check(best_fitness[0], outcome.evaluations) {
+          outcome.stop_reason = reason;
+          break;
+      }
   }
 
-  population.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-  (population[0].0.clone(), population[0].1)
+  population.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+  Ok((population[0].0.clone(), population[0].1, outcome))
+}
+
+/// Search strategy used by `run_optimization`.
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    Genetic(GaSettings),
+    Cmaes(CmaesSettings),
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::Genetic(GaSettings::default())
+    }
 }
 
+/// Runs `num_repeats` independent optimizations and returns the best, with how that
+/// repeat ended. Without a time budget the result depends only on the arguments and
+/// `seed`, not on `threads` or scheduling. Fails with `Invalid` when the objective,
+/// parameter updates or settings do not pass the `validation` checks, and if no
+/// candidate simulated without diverging.
+#[allow(clippy::too_many_arguments)]
 pub fn run_optimization(
-  target_metrics: &[(f64, String, f64)],
-  param_updates: Option<FxHashMap<String, (Option<f64>, Option<(f64, f64)>, Option<bool>)>>,
-  num_repeats: usize
-) -> (HemodynamicParams, f64) {
-  let cpus = num_cpus::get().max(8);
-  let threads_per_start = cpus / num_repeats;
-
-  let results: Vec<(HemodynamicParams, f64)> = (0..num_repeats)
+  objective: &Objective,
+  param_updates: Option<ParamUpdates>,
+  num_repeats: usize,
+  settings: &SimulationSettings,
+  algorithm: &Algorithm,
+  seed: u64,
+  threads: usize,
+  control: &RunControl,
+) -> Result<(HemodynamicParams, f64, RunOutcome), ModelError> {
+  if num_repeats == 0 {
+      return Err(ModelError::NoRepeats);
+  }
+  let mut errors = ValidationErrors::new();
+  for (i, condition) in objective.conditions.iter().enumerate() {
+      let prefix = format!("conditions[{}].", i);
+      validation::target_metrics(&condition.metrics, &prefix, &mut errors);
+      validation::waveform_targets(&condition.waveforms, &prefix, &mut errors);
+  }
+  let base = validation::param_updates(param_updates.as_ref(), &mut errors);
+  for (i, condition) in objective.conditions.iter().enumerate() {
+      validation::param_overrides(&condition.overrides, &base, &format!("conditions[{}].", i), &mut errors);
+  }
+  validation::solver(&settings.solver, &mut errors);
+  validation::steady_state(&settings.steady_state, &mut errors);
+  match algorithm {
+      Algorithm::Genetic(ga) => validation::ga_settings(ga, &mut errors),
+      Algorithm::Cmaes(cmaes) => validation::cmaes_settings(cmaes, &mut errors),
+  }
+  errors.into_result().map_err(ModelError::Invalid)?;
+
+  let threads_per_start = (threads / num_repeats).max(1);
+
+  let results: Vec<(HemodynamicParams, f64, RunOutcome)> = (0..num_repeats)
       .into_par_iter()
       .map(|repeat| {
-          println!("Starting repeat: {}/{}", repeat + 1, num_repeats);
-          run_single(target_metrics, param_updates.clone(), threads_per_start)
+          match algorithm {
+              Algorithm::Genetic(ga) => {
+                  run_single(repeat, seed, objective, &base, ga, threads_per_start, settings, control)
+              }
+              Algorithm::Cmaes(cmaes) => {
+                  cmaes::run_cmaes(repeat, seed, objective, &base, cmaes, threads_per_start, settings, control)
+              }
+          }
       })
-      .collect();
+      .collect::<Result<_, ModelError>>()?;
+
+  let best = results.into_iter().min_by(|a, b| a.1.total_cmp(&b.1)).ok_or(ModelError::NoRepeats)?;
+  if !best.1.is_finite() {
+      return Err(ModelError::Diverged);
+  }
+  Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stroke_volume_objective() -> Objective {
+      Objective::new(
+          vec![TargetMetric { metric: "stroke_volume".to_string(), target: 60.0, weight: 1.0, tolerance: 0.0 }],
+          Vec::new(),
+      )
+  }
+
+  #[test]
+  fn seeded_runs_do_not_depend_on_thread_count() {
+      let objective = stroke_volume_objective();
+      let settings = SimulationSettings::default();
+      let ga = GaSettings { population_size: 4, generations: 2, ..GaSettings::default() };
+      let cmaes = CmaesSettings { population_size: Some(4), generations: 2, ..CmaesSettings::default() };
+      for algorithm in [Algorithm::Genetic(ga), Algorithm::Cmaes(cmaes)] {
+          let run = |threads| {
+              run_optimization(&objective, None, 2, &settings, &algorithm, 7, threads, &RunControl::new()).unwrap()
+          };
+          let (single, single_fitness, _) = run(1);
+          let (parallel, parallel_fitness, _) = run(3);
+
+          assert_eq!(single_fitness.to_bits(), parallel_fitness.to_bits());
+          assert_eq!(single.parameters.len(), parallel.parameters.len());
+          for (name, param) in &single.parameters {
+              assert_eq!(param.value.to_bits(), parallel.parameters[name].value.to_bits(), "{}", name);
+          }
+      }
+  }
 
-  let (best_params, best_fitness) = results.into_iter().min_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap();
-  (best_params, best_fitness)
+  fn steady_metrics(params: &HemodynamicParams, solver: Solver) -> FxHashMap<String, f64> {
+      let settings = SimulationSettings { solver, ..SimulationSettings::default() };
+      let (sol, _) = simulate_steady_state(params, &settings).unwrap();
+      calculate_hemodynamic_metrics(&sol, params).unwrap()
+  }
+
+  #[test]
+  fn dopri5_agrees_with_rk4() {
+      let params = HemodynamicParams::default();
+      let rk4 = steady_metrics(&params, Solver::Rk4);
+      let dopri5 = steady_metrics(&params, Solver::Dopri5 { atol: 1e-6, rtol: 1e-6, max_step: 10.0 });
+      for name in ["stroke_volume", "mean_arterial_pressure", "left_ventricular_end_diastolic_volume"] {
+          let (a, b) = (rk4[name], dopri5[name]);
+          assert!((a - b).abs() <= 1e-3 * a.abs(), "{}: rk4 {} vs dopri5 {}", name, a, b);
+      }
+  }
+
+  #[test]
+  fn dopri5_reports_divergence() {
+      let mut params = HemodynamicParams::default();
+      params.parameters.get_mut("Cas_prox").unwrap().value = 1e-9;
+      let settings = SimulationSettings {
+          solver: Solver::Dopri5 { atol: 1e-6, rtol: 1e-6, max_step: 10.0 },
+          ..SimulationSettings::default()
+      };
+      assert_eq!(simulate_steady_state(&params, &settings).unwrap_err(), ModelError::Diverged);
+  }
+
+  #[test]
+  fn relaxation_time_constant_without_a_pressure_fall_is_zero() {
+      let t = [0.0, 2.0, 4.0, 6.0];
+      let closed = [0.0; 4];
+      let falling = [80.0, 40.0, 20.0, 10.0];
+      let tau = relaxation_time_constant(&t, &falling, &closed);
+      assert!((tau - 2.0 / 2f64.ln()).abs() < 1e-9, "{}", tau);
+      // A flat pressure has a zero slope, a rising one a positive slope.
+      assert_eq!(relaxation_time_constant(&t, &[50.0; 4], &closed), 0.0);
+      assert_eq!(relaxation_time_constant(&t, &[10.0, 5.0, 20.0, 40.0], &closed), 0.0);
+  }
+
+  #[test]
+  fn competent_valves_report_no_regurgitation() {
+      let metrics = steady_metrics(&HemodynamicParams::default(), Solver::Rk4);
+      for valve in ["aortic", "mitral", "pulmonary", "tricuspid"] {
+          assert_eq!(metrics[&format!("{}_regurgitant_volume", valve)], 0.0, "{}", valve);
+          assert_eq!(metrics[&format!("{}_regurgitant_fraction", valve)], 0.0, "{}", valve);
+      }
+
+      let mut leaky = HemodynamicParams::default();
+      leaky.parameters.get_mut("Rmvr").unwrap().value = 0.05;
+      let metrics = steady_metrics(&leaky, Solver::Rk4);
+      assert!(metrics["mitral_regurgitant_volume"] > 0.0);
+  }
+
+  #[test]
+  fn overrides_follow_the_parameter_rules() {
+      let mut objective = stroke_volume_objective();
+      let overrides = [
+          ("HR", ParamOverride { value: Some(600.0), scale: None }),
+          ("LV_Ees", ParamOverride { value: Some(-1.0), scale: None }),
+          ("LV_V0", ParamOverride { value: Some(0.0), scale: None }),
+          ("Rcs", ParamOverride { value: None, scale: Some(2.0) }),
+      ];
+      objective.conditions[0].overrides = overrides.into_iter().map(|(name, change)| (name.to_string(), change)).collect();
+      let algorithm = Algorithm::Genetic(GaSettings::default());
+      let error = run_optimization(&objective, None, 1, &SimulationSettings::default(), &algorithm, 0, 1, &RunControl::new())
+          .unwrap_err();
+      let ModelError::Invalid(errors) = error else { panic!("{:?}", error) };
+      let fields: Vec<&str> = errors.errors.iter().map(|error| error.field.as_str()).collect();
+      assert_eq!(fields, ["conditions[0].overrides.HR", "conditions[0].overrides.LV_Ees"]);
+  }
+
+  #[test]
+  fn heart_rate_outside_range_is_rejected() {
+      for hr in [1e-6, 0.0, 1000.0, f64::NAN] {
+          let mut params = HemodynamicParams::default();
+          params.parameters.get_mut("HR").unwrap().value = hr;
+          let error = simulate_steady_state(&params, &SimulationSettings::default()).unwrap_err();
+          assert!(matches!(error, ModelError::Invalid(ref errors) if errors.errors[0].field == "HR"), "{}", hr);
+      }
+  }
 }
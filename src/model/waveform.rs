@@ -0,0 +1,148 @@
+#![allow(non_snake_case)]
+
+use crate::error::{ModelError, ValidationErrors};
+use crate::model::parameters::CompiledParams;
+use crate::model::simulation::{calculate_pressures_and_flows, SimulationResult};
+use crate::validation;
+use serde::{Deserialize, Serialize};
+
+/// Points of the common phase grid measured and simulated beats are compared on.
+const PHASE_POINTS: usize = 256;
+
+/// Pressure traces that can be fitted against measured waveforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureSignal {
+    /// Aop, the proximal aortic pressure.
+    ArterialPressure,
+    /// PAP, the proximal pulmonary arterial pressure.
+    PulmonaryArterialPressure,
+    /// Left atrial pressure, which a wedge catheter measures.
+    PulmonaryCapillaryWedgePressure,
+    /// Right atrial pressure.
+    CentralVenousPressure,
+    LeftVentricularPressure,
+    RightVentricularPressure,
+}
+
+impl PressureSignal {
+    fn sample(self, t: f64, state: &[f64; 11], p: &CompiledParams) -> f64 {
+        let (pressures, _) = calculate_pressures_and_flows(t, state, p);
+        let [Plv, Pla, Prv, Pra, Iasp, Iapp, _, _] = pressures;
+        match self {
+            Self::ArterialPressure => state[8] / p.Cas_prox + Iasp * p.Ras_prox,
+            Self::PulmonaryArterialPressure => state[10] / p.Cap_prox + Iapp * p.Rap_prox,
+            Self::PulmonaryCapillaryWedgePressure => Pla,
+            Self::CentralVenousPressure => Pra,
+            Self::LeftVentricularPressure => Plv,
+            Self::RightVentricularPressure => Prv,
+        }
+    }
+}
+
+/// One measured beat of a pressure trace. The beat is stretched onto the simulated
+/// cycle and shifted in phase to its best match before the error is taken, so
+/// neither the heart rate nor the trigger point of the recording has to match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaveformTarget {
+    pub signal: PressureSignal,
+    /// Sample times (ms), strictly increasing, covering one beat.
+    pub t: Vec<f64>,
+    /// Measured pressures (mmHg) at `t`.
+    pub values: Vec<f64>,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// Length of the measured beat (ms). Defaults to the span of `t` plus one mean
+    /// sample interval, i.e. `t` is assumed not to repeat the first sample.
+    pub period: Option<f64>,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl WaveformTarget {
+    fn period(&self) -> f64 {
+        self.period.unwrap_or_else(|| {
+            let span = self.t[self.t.len() - 1] - self.t[0];
+            span * self.t.len() as f64 / (self.t.len() - 1) as f64
+        })
+    }
+
+    /// The measured beat resampled on the phase grid, treating it as periodic.
+    fn phase_samples(&self) -> Vec<f64> {
+        let period = self.period();
+        let mut phases: Vec<(f64, f64)> = self
+            .t
+            .iter()
+            .zip(&self.values)
+            .map(|(&time, &value)| (((time - self.t[0]) / period).rem_euclid(1.0), value))
+            .collect();
+        phases.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (first, last) = (phases[0], phases[phases.len() - 1]);
+        phases.insert(0, (last.0 - 1.0, last.1));
+        phases.push((first.0 + 1.0, first.1));
+
+        (0..PHASE_POINTS)
+            .map(|k| interpolate(&phases, k as f64 / PHASE_POINTS as f64))
+            .collect()
+    }
+}
+
+/// Linear interpolation in `points`, sorted by x and bracketing `x`.
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    let upper = points.partition_point(|p| p.0 < x).clamp(1, points.len() - 1);
+    let (x0, y0) = points[upper - 1];
+    let (x1, y1) = points[upper];
+    if x1 > x0 { y0 + (y1 - y0) * (x - x0) / (x1 - x0) } else { y0 }
+}
+
+/// The simulated cycle `sol` (one beat, first and last sample a period apart)
+/// resampled on the phase grid.
+fn simulated_phase_samples(sol: &SimulationResult, signal: PressureSignal, p: &CompiledParams) -> Vec<f64> {
+    let t = sol.t();
+    let (start, end) = (t[0], t[t.len() - 1]);
+    let points: Vec<(f64, f64)> = t
+        .iter()
+        .zip(sol.y())
+        .map(|(&time, state)| ((time - start) / (end - start), signal.sample(time, state, p)))
+        .collect();
+    (0..PHASE_POINTS)
+        .map(|k| interpolate(&points, k as f64 / PHASE_POINTS as f64))
+        .collect()
+}
+
+/// Best phase alignment of a measured beat against the simulated cycle.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WaveformFit {
+    /// Mean squared difference (mmHg²) at the best shift.
+    pub mean_squared_error: f64,
+    /// Fraction of a cycle the measured beat was shifted by.
+    pub phase_shift: f64,
+}
+
+/// Aligns `target` to the simulated steady-state beat `sol` over all circular shifts.
+/// Fails with `Invalid` when `target` does not pass `validation::waveform_targets`.
+pub fn fit_waveform(sol: &SimulationResult, target: &WaveformTarget, p: &CompiledParams) -> Result<WaveformFit, ModelError> {
+    let mut errors = ValidationErrors::new();
+    validation::waveform_targets(std::slice::from_ref(target), "", &mut errors);
+    errors.into_result().map_err(ModelError::Invalid)?;
+
+    let simulated = simulated_phase_samples(sol, target.signal, p);
+    let measured = target.phase_samples();
+    Ok((0..PHASE_POINTS)
+        .map(|shift| {
+            let squared_error: f64 = measured
+                .iter()
+                .enumerate()
+                .map(|(k, value)| (simulated[(k + shift) % PHASE_POINTS] - value).powi(2))
+                .sum();
+            WaveformFit {
+                mean_squared_error: squared_error / PHASE_POINTS as f64,
+                phase_shift: shift as f64 / PHASE_POINTS as f64,
+            }
+        })
+        .min_by(|a, b| a.mean_squared_error.total_cmp(&b.mean_squared_error))
+        .unwrap_or(WaveformFit { mean_squared_error: 0.0, phase_shift: 0.0 }))
+}
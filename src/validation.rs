@@ -0,0 +1,234 @@
+//! Input checks for parameters, targets and optimizer settings. Every check runs
+//! so a rejected input lists all offending fields at once.
+
+use crate::error::ValidationErrors;
+use crate::model::cmaes::CmaesSettings;
+use crate::model::parameters::{HemodynamicParams, ParamOverride, ParamUpdates};
+use crate::model::polish::PolishSettings;
+use crate::model::waveform::WaveformTarget;
+use crate::model::simulation::{
+    GaSettings, MutationShape, Solver, SteadyStateSettings, StoppingCriteria, TargetMetric, HEART_RATE_RANGE,
+    METRIC_NAMES,
+};
+
+use fxhash::FxHashMap;
+
+fn positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+/// Checks targets; `prefix` locates them inside the request, e.g. `conditions[0].`.
+pub fn target_metrics(targets: &[TargetMetric], prefix: &str, errors: &mut ValidationErrors) {
+    for (i, target) in targets.iter().enumerate() {
+        let field = format!("{}target_metrics[{}]", prefix, i);
+        errors.check(
+            METRIC_NAMES.contains(&target.metric.as_str()),
+            &field,
+            format!("unknown metric '{}'", target.metric),
+        );
+        errors.check(target.target.is_finite(), &field, "target must be a finite number");
+        errors.check(target.weight.is_finite() && target.weight >= 0.0, &field, "weight must be a non-negative number");
+        errors.check(
+            target.tolerance.is_finite() && target.tolerance >= 0.0,
+            &field,
+            "tolerance must be a non-negative number",
+        );
+    }
+}
+
+pub fn waveform_targets(targets: &[WaveformTarget], prefix: &str, errors: &mut ValidationErrors) {
+    for (i, target) in targets.iter().enumerate() {
+        let field = |name: &str| format!("{}waveform_targets[{}].{}", prefix, i, name);
+        errors.check(target.t.len() >= 2, field("t"), "at least two samples are required");
+        errors.check(target.t.len() == target.values.len(), field("values"), "must have as many samples as t");
+        errors.check(
+            target.t.iter().all(|time| time.is_finite()) && target.t.windows(2).all(|pair| pair[0] < pair[1]),
+            field("t"),
+            "must be finite and strictly increasing",
+        );
+        errors.check(target.values.iter().all(|value| value.is_finite()), field("values"), "must be finite numbers");
+        errors.check(target.weight.is_finite() && target.weight >= 0.0, field("weight"), "must not be negative");
+        if let Some(period) = target.period {
+            let span = target.t.last().zip(target.t.first()).map_or(0.0, |(last, first)| last - first);
+            errors.check(positive(period) && period > span, field("period"), "must be longer than the span of t");
+        }
+    }
+}
+
+/// Every parameter must be positive, except the few that are legitimately zero:
+/// chamber dead volumes and activation delays, the stenotic valve resistances
+/// (zero in a healthy heart) and the initial venous volume.
+fn allows_zero(name: &str) -> bool {
+    match name.split_once('_') {
+        Some(("LV" | "LA" | "RV" | "RA", rest)) => matches!(rest, "V0" | "AV_delay"),
+        _ => ["Ravs", "Rmvs", "Rpvs", "Rtvs", "Qvs_initial"].contains(&name),
+    }
+}
+
+/// Checks `updates` against the default parameter set and returns the resolved parameters.
+pub fn param_updates(updates: Option<&ParamUpdates>, errors: &mut ValidationErrors) -> HemodynamicParams {
+    let mut params = HemodynamicParams::default();
+    let Some(updates) = updates else {
+        return params;
+    };
+
+    let mut names: Vec<&String> = updates.keys().collect();
+    names.sort();
+    for name in names {
+        let field = format!("param_updates.{}", name);
+        if params.update(name, &updates[name]).is_err() {
+            errors.add(field, "unknown parameter");
+            continue;
+        }
+        let param = &params.parameters[name];
+        let (lo, hi) = param.range;
+        // The optimizer draws fitted values from the range, so only a value the
+        // client sent has to lie inside it.
+        let value_given = updates[name].value.is_some();
+
+        errors.check(param.value.is_finite(), &field, "value must be a finite number");
+        if !(lo.is_finite() && hi.is_finite() && lo <= hi) {
+            errors.add(&field, "range must satisfy min <= max");
+        } else if param.fitting {
+            errors.check(lo < hi, &field, "a fitted parameter needs a range with min < max");
+            errors.check(
+                !value_given || (lo..=hi).contains(&param.value),
+                &field,
+                format!("value {} is outside the range [{}, {}]", param.value, lo, hi),
+            );
+        }
+        let (lowest, highest) = if param.fitting { (lo, hi) } else { (param.value, param.value) };
+        value_limits(name, lowest, highest, &field, errors);
+    }
+    params
+}
+
+/// Sign rule for a parameter taking values in `lowest..=highest`; the heart rate
+/// must instead stay inside `HEART_RATE_RANGE`.
+fn value_limits(name: &str, lowest: f64, highest: f64, field: &str, errors: &mut ValidationErrors) {
+    if name == "HR" {
+        let (min, max) = HEART_RATE_RANGE;
+        errors.check(
+            min <= lowest && highest <= max,
+            field,
+            format!("must be between {} and {} bpm", min, max),
+        );
+    } else if allows_zero(name) {
+        errors.check(lowest >= 0.0, field, "must not be negative");
+    } else {
+        errors.check(lowest > 0.0, field, "must be positive");
+    }
+}
+
+/// Checks condition overrides of `shared`, the parameters returned by `param_updates`.
+/// The overridden value, or every value a scale can give a fitted parameter, must
+/// follow the same rules as the shared one.
+pub fn param_overrides(
+    overrides: &FxHashMap<String, ParamOverride>,
+    shared: &HemodynamicParams,
+    prefix: &str,
+    errors: &mut ValidationErrors,
+) {
+    let mut names: Vec<&String> = overrides.keys().collect();
+    names.sort();
+    for name in names {
+        let field = format!("{}overrides.{}", prefix, name);
+        let Some(param) = shared.parameters.get(name) else {
+            errors.add(field, "unknown parameter");
+            continue;
+        };
+        let change = overrides[name];
+        match (change.value, change.scale) {
+            (Some(value), None) if !value.is_finite() => errors.add(&field, "value must be a finite number"),
+            (Some(value), None) => value_limits(name, value, value, &field, errors),
+            (None, Some(scale)) if !positive(scale) => errors.add(&field, "scale must be positive"),
+            (None, Some(scale)) => {
+                let (lo, hi) = if param.fitting { param.range } else { (param.value, param.value) };
+                value_limits(name, lo * scale, hi * scale, &field, errors);
+            }
+            _ => errors.add(&field, "exactly one of value and scale is required"),
+        }
+    }
+}
+
+pub fn solver(solver: &Solver, errors: &mut ValidationErrors) {
+    if let Solver::Dopri5 { atol, rtol, max_step } = *solver {
+        errors.check(positive(atol), "solver.atol", "must be positive");
+        errors.check(positive(rtol), "solver.rtol", "must be positive");
+        errors.check(positive(max_step), "solver.max_step", "must be positive");
+    }
+}
+
+pub fn steady_state(settings: &SteadyStateSettings, errors: &mut ValidationErrors) {
+    errors.check(positive(settings.tolerance), "steady_state.tolerance", "must be positive");
+    errors.check((1..=1000).contains(&settings.max_beats), "steady_state.max_beats", "must be between 1 and 1000");
+}
+
+pub fn num_repeats(num_repeats: usize, errors: &mut ValidationErrors) {
+    errors.check((1..=64).contains(&num_repeats), "num_repeats", "must be between 1 and 64");
+}
+
+pub fn ga_settings(ga: &GaSettings, errors: &mut ValidationErrors) {
+    let field = |name: &str| format!("optimizer_settings.{}", name);
+    errors.check((2..=10_000).contains(&ga.population_size), field("population_size"), "must be between 2 and 10000");
+    errors.check((1..=100_000).contains(&ga.generations), field("generations"), "must be between 1 and 100000");
+    errors.check(ga.elite_count < ga.population_size, field("elite_count"), "must be smaller than population_size");
+    errors.check(
+        ga.tournament_size >= 1 && ga.tournament_size <= ga.population_size,
+        field("tournament_size"),
+        "must be between 1 and population_size",
+    );
+    errors.check(
+        (0.0..=1.0).contains(&ga.crossover_probability),
+        field("crossover_probability"),
+        "must be between 0 and 1",
+    );
+    for (name, rate) in [("mutation.initial_rate", ga.mutation.initial_rate), ("mutation.final_rate", ga.mutation.final_rate)] {
+        errors.check((0.0..=1.0).contains(&rate), field(name), "must be between 0 and 1");
+    }
+    if ga.mutation.shape == MutationShape::Exponential {
+        errors.check(
+            ga.mutation.initial_rate > 0.0 && ga.mutation.final_rate > 0.0,
+            field("mutation.shape"),
+            "mutation rates must be positive for the exponential shape",
+        );
+    }
+}
+
+pub fn cmaes_settings(cmaes: &CmaesSettings, errors: &mut ValidationErrors) {
+    errors.check(positive(cmaes.sigma), "cmaes_settings.sigma", "must be positive");
+    errors.check((1..=100_000).contains(&cmaes.generations), "cmaes_settings.generations", "must be between 1 and 100000");
+    if let Some(population_size) = cmaes.population_size {
+        errors.check(
+            (4..=10_000).contains(&population_size),
+            "cmaes_settings.population_size",
+            "must be between 4 and 10000",
+        );
+    }
+}
+
+pub fn polish_settings(polish: &PolishSettings, errors: &mut ValidationErrors) {
+    errors.check(polish.max_evaluations >= 1, "polish.max_evaluations", "must be at least 1");
+    errors.check(
+        polish.initial_step > 0.0 && polish.initial_step <= 1.0,
+        "polish.initial_step",
+        "must be in (0, 1]",
+    );
+    errors.check(polish.tolerance >= 0.0, "polish.tolerance", "must not be negative");
+}
+
+pub fn stopping(stopping: &StoppingCriteria, errors: &mut ValidationErrors) {
+    errors.check(stopping.improvement_window >= 1, "stopping.improvement_window", "must be at least 1");
+    if let Some(patience) = stopping.patience {
+        errors.check(patience >= 1, "stopping.patience", "must be at least 1");
+    }
+    if let Some(epsilon) = stopping.min_relative_improvement {
+        errors.check(epsilon.is_finite() && epsilon >= 0.0, "stopping.min_relative_improvement", "must not be negative");
+    }
+    if let Some(seconds) = stopping.max_seconds {
+        errors.check(positive(seconds), "stopping.max_seconds", "must be positive");
+    }
+    if let Some(evaluations) = stopping.max_evaluations {
+        errors.check(evaluations >= 1, "stopping.max_evaluations", "must be at least 1");
+    }
+}